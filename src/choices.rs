@@ -1,21 +1,58 @@
-use super::{Error, Experiment, Response, Stimulus, StimulusAttribute};
+use super::{DecisiveValue, Error, Experiment, Response, Stimulus};
 use itertools::Itertools;
-use rand::{seq::SliceRandom, thread_rng};
-use serde::{Deserialize, Serialize};
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_value::Value;
+use sha2::{Digest, Sha256};
 use std::convert::TryInto;
-use std::{collections::HashMap, iter};
+use std::{collections::BTreeMap, iter, time::SystemTime};
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct CorrectChoices(HashMap<StimulusAttribute, Response>);
+/// The newest correct choices file format this version of the program can read and write
+pub const CHOICES_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct CorrectChoices(BTreeMap<DecisiveValue, Response>);
+
+impl iter::FromIterator<(DecisiveValue, Response)> for CorrectChoices {
+    fn from_iter<I: IntoIterator<Item = (DecisiveValue, Response)>>(choices: I) -> Self {
+        CorrectChoices(choices.into_iter().collect())
+    }
+}
+
+/// How the decisive values of an experiment differ from the ones in its correct choices
+#[derive(Default, PartialEq, Debug)]
+pub struct ChoicesDiff {
+    /// values in the experiment without a correct choice
+    pub added: Vec<DecisiveValue>,
+    /// values with a correct choice that are no longer in the experiment
+    pub removed: Vec<DecisiveValue>,
+}
+
+impl ChoicesDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
 
 impl CorrectChoices {
     pub fn get(&self, key: &Stimulus) -> Result<&Response, Error> {
-        let key = key.decisive_attribute();
+        let key = key.decisive_value();
         self.0
-            .get(key)
+            .get(&key)
             .ok_or_else(|| Error::StimMissingFromCorrectChoices(key.clone()))
     }
 
+    pub fn response(&self, value: &DecisiveValue) -> Option<&Response> {
+        self.0.get(value)
+    }
+
+    /// The SHA-256 hash of the correct choices, which is the same wherever they are stored
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("correct choices can always be serialized");
+        experiment_hash(&json)
+    }
+
     fn choices(&self) -> [Response; 2] {
         self.0
             .values()
@@ -45,47 +82,227 @@ impl CorrectChoices {
         )
     }
 
-    pub fn random(experiment: &Experiment) -> Result<Self, Error> {
-        let all_values = experiment
-            .list_attribute_values(experiment.decisive_attribute())
-            .unwrap();
-        Self::random_with_choices(experiment.choices(), all_values)
+    /// Reads decisive values written as one string, such as `"song, 30"`, as the
+    /// values of the experiment's decisive attributes if it has more than one
+    pub fn split_values(&mut self, experiment: &Experiment) -> Result<(), Error> {
+        let n_attributes = experiment.decisive_attributes().len();
+        self.0 = std::mem::take(&mut self.0)
+            .into_iter()
+            .map(|(value, response)| Ok((value.split(n_attributes)?, response)))
+            .collect::<Result<_, Error>>()?;
+        Ok(())
+    }
+
+    /// Compares the decisive values in `experiment` with the ones that have a correct choice
+    pub fn reconcile(&self, experiment: &Experiment) -> ChoicesDiff {
+        let current = experiment.decisive_values();
+        ChoicesDiff {
+            added: current
+                .iter()
+                .filter(|value| !self.0.contains_key(value))
+                .cloned()
+                .collect(),
+            removed: self
+                .0
+                .keys()
+                .filter(|value| !current.contains(value))
+                .sorted_by_key(|value| value.to_string())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Assigns correct choices to `values` without changing any existing assignment.
+    /// The new values are split as evenly as possible between the choices, and any
+    /// remainder goes to the choices that were assigned least so far.
+    pub fn extend(&mut self, experiment: &Experiment, values: &[DecisiveValue]) {
+        let mut rng = thread_rng();
+        let mut choices = experiment.choices();
+        choices.shuffle(&mut rng);
+        choices.sort_by_key(|choice| self.0.values().filter(|&r| r == choice).count());
+        let assigned = assign(&choices, values, &mut rng);
+        self.0.extend(assigned);
     }
 
-    fn random_with_choices<'a, I>(mut choices: Vec<Response>, all_values: I) -> Result<Self, Error>
+    /// Randomly assigns a correct choice to each decisive value; the same seed gives
+    /// the same assignment
+    pub fn random(experiment: &Experiment, seed: u64) -> Result<Self, Error> {
+        let all_values = experiment.decisive_values();
+        let mut rng = StdRng::seed_from_u64(seed);
+        Self::random_with_choices(experiment.choices(), all_values.iter(), &mut rng)
+    }
+
+    fn random_with_choices<'a, I, R>(
+        mut choices: Vec<Response>,
+        all_values: I,
+        rng: &mut R,
+    ) -> Result<Self, Error>
     where
-        I: IntoIterator<Item = &'a StimulusAttribute>,
+        I: IntoIterator<Item = &'a DecisiveValue>,
+        R: Rng,
     {
-        let all_values: Vec<_> = all_values.into_iter().collect();
         if choices.is_empty() {
             return Err(Error::EmptyChoices);
         }
-        let mut rng = thread_rng();
-        choices.shuffle(&mut rng);
-        let stimuli_per_response = all_values.len() / choices.len();
-        let remainder = all_values.len() % choices.len();
-        // we create a vector with one response per stimulus,
-        // with evenly divided assignment as much as possible
-        let mut matched_choices: Vec<Response> = choices
-            .iter()
-            .map(|&c| iter::repeat(c).take(stimuli_per_response))
-            .flatten()
-            .chain(choices.iter().take(remainder).copied())
-            .collect();
-        matched_choices.shuffle(&mut rng);
-        Ok(CorrectChoices(
-            all_values
-                .into_iter()
-                .cloned()
-                .zip(matched_choices)
-                .collect(),
-        ))
+        choices.shuffle(rng);
+        Ok(CorrectChoices(assign(&choices, all_values, rng)))
     }
 }
 
+/// A correct choices file: either the correct choices with a description of how they
+/// were made, or, as written by older versions of this program, just the correct choices
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ChoicesFile {
+    Versioned(VersionedChoices),
+    Legacy(CorrectChoices),
+}
+
+/// The format is picked by `version`, so that a mistake in a versioned file is
+/// reported as it is, and a file without `version` is read as a legacy file
+impl<'de> Deserialize<'de> for ChoicesFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let version = match &value {
+            Value::Map(map) => map.get(&Value::String(String::from("version"))).cloned(),
+            _ => None,
+        };
+        match version {
+            None => CorrectChoices::deserialize(value)
+                .map(ChoicesFile::Legacy)
+                .map_err(de::Error::custom),
+            Some(version) => {
+                let version = u32::deserialize(version)
+                    .map_err(|e| de::Error::custom(format!("invalid `version`: {}", e)))?;
+                if version > CHOICES_FORMAT_VERSION {
+                    return Err(de::Error::custom(Error::UnsupportedChoicesVersion(version)));
+                }
+                VersionedChoices::deserialize(value)
+                    .map(ChoicesFile::Versioned)
+                    .map_err(de::Error::custom)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct VersionedChoices {
+    pub version: u32,
+    /// when the correct choices were made, in RFC 3339 format
+    pub created: String,
+    /// the seed the correct choices were randomly assigned with, if it is known
+    pub seed: Option<u64>,
+    /// the SHA-256 hash of the experiment file the correct choices were made for
+    pub experiment: String,
+    pub choices: Vec<Response>,
+    pub tool_version: String,
+    pub assignments: CorrectChoices,
+}
+
+impl VersionedChoices {
+    pub fn new(
+        assignments: CorrectChoices,
+        experiment_source: &[u8],
+        choices: Vec<Response>,
+        seed: Option<u64>,
+    ) -> Self {
+        VersionedChoices {
+            version: CHOICES_FORMAT_VERSION,
+            created: now(),
+            seed,
+            experiment: experiment_hash(experiment_source),
+            choices,
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            assignments,
+        }
+    }
+}
+
+impl ChoicesFile {
+    pub fn correct_choices(&self) -> &CorrectChoices {
+        match self {
+            ChoicesFile::Versioned(file) => &file.assignments,
+            ChoicesFile::Legacy(choices) => choices,
+        }
+    }
+
+    pub fn correct_choices_mut(&mut self) -> &mut CorrectChoices {
+        match self {
+            ChoicesFile::Versioned(file) => &mut file.assignments,
+            ChoicesFile::Legacy(choices) => choices,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, ChoicesFile::Legacy(_))
+    }
+
+    /// Records that correct choices were assigned to new values of the experiment in
+    /// `experiment_source`. The seed is dropped, since it no longer gives every
+    /// assignment.
+    pub fn extended(&mut self, experiment_source: &[u8]) {
+        if let ChoicesFile::Versioned(file) = self {
+            file.created = now();
+            file.seed = None;
+            file.experiment = experiment_hash(experiment_source);
+            file.tool_version = String::from(env!("CARGO_PKG_VERSION"));
+        }
+    }
+
+    /// Converts a legacy file to the current format, leaving the correct choices as
+    /// they are. The seed of a legacy file is not known.
+    pub fn migrate(self, experiment_source: &[u8], choices: Vec<Response>) -> Self {
+        match self {
+            ChoicesFile::Legacy(assignments) => ChoicesFile::Versioned(VersionedChoices::new(
+                assignments,
+                experiment_source,
+                choices,
+                None,
+            )),
+            versioned => versioned,
+        }
+    }
+}
+
+/// The current time in RFC 3339 format
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// The SHA-256 hash of an experiment file, as a hex string
+pub fn experiment_hash(source: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(source))
+}
+
+/// Randomly matches each value with a response, dividing the values between the
+/// `choices` as evenly as possible, with the remainder going to the first choices
+fn assign<'a, I, R>(
+    choices: &[Response],
+    values: I,
+    rng: &mut R,
+) -> BTreeMap<DecisiveValue, Response>
+where
+    I: IntoIterator<Item = &'a DecisiveValue>,
+    R: Rng,
+{
+    let values: Vec<_> = values.into_iter().collect();
+    let stimuli_per_response = values.len() / choices.len();
+    let remainder = values.len() % choices.len();
+    // we create a vector with one response per stimulus,
+    // with evenly divided assignment as much as possible
+    let mut matched_choices: Vec<Response> = choices
+        .iter()
+        .flat_map(|&c| iter::repeat_n(c, stimuli_per_response))
+        .chain(choices.iter().take(remainder).copied())
+        .collect();
+    matched_choices.shuffle(rng);
+    values.into_iter().cloned().zip(matched_choices).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::StimulusAttribute;
 
     #[test]
     fn random_correctchoices() {
@@ -93,8 +310,11 @@ mod tests {
         let all_values: Vec<_> = vec!["a", "b", "c", "d"]
             .into_iter()
             .map(StimulusAttribute::from)
+            .map(DecisiveValue::from)
             .collect();
-        let correct = CorrectChoices::random_with_choices(choices, all_values.iter()).unwrap();
+        let correct =
+            CorrectChoices::random_with_choices(choices, all_values.iter(), &mut thread_rng())
+                .unwrap();
         let n_stimuli = 4;
         let n_choices = 2;
         let by_response = |resp| correct.0.values().filter(|&&x| x == resp).count();
@@ -103,4 +323,73 @@ mod tests {
         assert!(left_count <= n_stimuli / n_choices + 1);
         assert!(right_count <= n_stimuli / n_choices + 1);
     }
+
+    #[test]
+    fn extend_correctchoices() {
+        let experiment: Experiment = crate::test_experiment(
+            "{}",
+            "{format: '{a}', decisive_attribute: a, a: {values: [a, b, c, d, e]}}",
+        )
+        .unwrap();
+        let value = |v| DecisiveValue::from(StimulusAttribute::from(v));
+        let mut correct = CorrectChoices(
+            vec![
+                (value("a"), Response::PeckLeft),
+                (value("b"), Response::PeckLeft),
+                (value("z"), Response::PeckRight),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let diff = correct.reconcile(&experiment);
+        assert_eq!(diff.added, vec![value("c"), value("d"), value("e")]);
+        assert_eq!(diff.removed, vec![value("z")]);
+        correct.extend(&experiment, &diff.added);
+        assert_eq!(correct.response(&value("a")), Some(&Response::PeckLeft));
+        assert_eq!(correct.response(&value("z")), Some(&Response::PeckRight));
+        // one new value each, and the extra one to the least used response
+        let new_right = diff
+            .added
+            .iter()
+            .filter(|v| correct.response(v) == Some(&Response::PeckRight))
+            .count();
+        assert_eq!(new_right, 2);
+    }
+
+    #[test]
+    fn read_choices_file() {
+        let legacy: ChoicesFile = serde_yaml::from_str("a: peck_left\nb: peck_right").unwrap();
+        assert!(legacy.is_legacy());
+        let migrated = legacy.migrate(b"experiment", vec![Response::PeckLeft]);
+        let written = serde_yaml::to_string(&migrated).unwrap();
+        let versioned: ChoicesFile = serde_yaml::from_str(&written).unwrap();
+        assert!(!versioned.is_legacy());
+        let a = DecisiveValue::from(StimulusAttribute::from("a"));
+        assert_eq!(
+            versioned.correct_choices().response(&a),
+            Some(&Response::PeckLeft)
+        );
+        let newer = written.replace("version: 1", "version: 99");
+        let error = serde_yaml::from_str::<ChoicesFile>(&newer).err().unwrap();
+        assert!(error.to_string().contains("format version 99"));
+        let misspelled = written.replace("created:", "craeted:");
+        let error = serde_yaml::from_str::<ChoicesFile>(&misspelled)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("created"));
+        let mut extended = ChoicesFile::Versioned(VersionedChoices::new(
+            versioned.correct_choices().clone(),
+            b"experiment",
+            vec![Response::PeckLeft],
+            Some(1),
+        ));
+        extended.extended(b"changed experiment");
+        match extended {
+            ChoicesFile::Versioned(file) => {
+                assert_eq!(file.seed, None);
+                assert_eq!(file.experiment, experiment_hash(b"changed experiment"));
+            }
+            ChoicesFile::Legacy(_) => unreachable!(),
+        }
+    }
 }
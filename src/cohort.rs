@@ -0,0 +1,168 @@
+use super::{make_subject_configs, ConfigWithParams, Error, Experiment, Subject};
+use itertools::Itertools;
+use serde::Serialize;
+
+/// One config in a cohort's schedule
+#[derive(Serialize, Debug)]
+pub struct ScheduleRow {
+    pub subject: String,
+    pub inverted: String,
+    /// the position of the config's set in the subject's order of sets, from 1
+    pub session: usize,
+    pub set: String,
+    pub config: String,
+}
+
+/// Orders of `n` items in which each item comes first equally often, and comes
+/// right after each other item equally often (a Williams design). There are `n`
+/// orders if `n` is even, and `2n` if it is odd.
+pub fn balanced_latin_square(n: usize) -> Vec<Vec<usize>> {
+    // 0, 1, n - 1, 2, n - 2, ...
+    let first: Vec<usize> = (0..n)
+        .map(|j| {
+            if j % 2 == 1 {
+                j / 2 + 1
+            } else {
+                (n - j / 2) % n
+            }
+        })
+        .collect();
+    let mut rows: Vec<Vec<usize>> = (0..n)
+        .map(|r| first.iter().map(|x| (x + r) % n).collect())
+        .collect();
+    if n % 2 == 1 {
+        let reversed: Vec<Vec<usize>> = rows
+            .iter()
+            .map(|row| row.iter().rev().copied().collect())
+            .collect();
+        rows.extend(reversed);
+    }
+    rows
+}
+
+/// A subject's configs, each with its session: the sets are sorted by name and
+/// then ordered by the subject's row of the balanced Latin square
+pub fn cohort_configs<'a>(
+    experiment: &'a Experiment,
+    id: &str,
+    subject: &Subject,
+) -> Result<Vec<(usize, ConfigWithParams<'a>)>, Error> {
+    let configs = make_subject_configs(experiment, id, subject)?;
+    let sets: Vec<String> = configs
+        .iter()
+        .filter_map(|(_, attributes)| attributes.get("set"))
+        .map(ToString::to_string)
+        .unique()
+        .sorted()
+        .collect();
+    let square = balanced_latin_square(sets.len());
+    let order = &square[subject.sequence.unwrap_or(0) % square.len()];
+    Ok(configs
+        .into_iter()
+        .map(|(config, attributes)| {
+            let set = attributes["set"].to_string();
+            let position = sets.iter().position(|s| *s == set).unwrap();
+            let session = order.iter().position(|&i| i == position).unwrap() + 1;
+            (session, (config, attributes))
+        })
+        .sorted_by_key(|(session, _)| *session)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CorrectChoices;
+    use std::collections::HashMap;
+
+    #[test]
+    fn cohort_schedule() {
+        let experiment: Experiment = crate::test_experiment(
+            "{name_format: 'x', stimuli_subsets: {one: [a], two: [b], three: [c]}}",
+            "{format: '{fg}', decisive_attribute: fg, fg: {values: [a, b, c]}}",
+        )
+        .unwrap();
+        let correct_choices = CorrectChoices::random(&experiment, 1).unwrap();
+        let schedule = |id: &str, inverted, sequence| {
+            let subject = Subject {
+                inverted,
+                correct_choices: correct_choices.clone(),
+                sequence: Some(sequence),
+            };
+            cohort_configs(&experiment, id, &subject)
+                .unwrap()
+                .into_iter()
+                .map(|(session, (config, attributes))| {
+                    let stimuli: Vec<_> = config.stimuli().map(|s| s.name().to_string()).collect();
+                    let name = format!(
+                        "x-subject{}-set{}-inverted{}",
+                        attributes["subject"], attributes["set"], attributes["inverted"]
+                    );
+                    (session, name, stimuli)
+                })
+                .collect::<Vec<_>>()
+        };
+        // the sets sorted by name are one, three, two, and the first two rows of the
+        // square order them 0, 1, 2 and 1, 2, 0
+        assert_eq!(
+            schedule("C1", false, 0),
+            vec![
+                (
+                    1,
+                    String::from("x-subjectC1-setone-invertedNo"),
+                    vec!["a".into()]
+                ),
+                (
+                    2,
+                    String::from("x-subjectC1-setthree-invertedNo"),
+                    vec!["c".into()]
+                ),
+                (
+                    3,
+                    String::from("x-subjectC1-settwo-invertedNo"),
+                    vec!["b".into()]
+                ),
+            ]
+        );
+        assert_eq!(
+            schedule("C2", true, 1),
+            vec![
+                (
+                    1,
+                    String::from("x-subjectC2-setthree-invertedYes"),
+                    vec!["c".into()]
+                ),
+                (
+                    2,
+                    String::from("x-subjectC2-settwo-invertedYes"),
+                    vec!["b".into()]
+                ),
+                (
+                    3,
+                    String::from("x-subjectC2-setone-invertedYes"),
+                    vec!["a".into()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn carryover_balance() {
+        for n in 2..7 {
+            let square = balanced_latin_square(n);
+            let mut followers = HashMap::new();
+            for row in square.iter() {
+                assert_eq!(
+                    row.iter().sorted().copied().collect::<Vec<_>>(),
+                    (0..n).collect::<Vec<_>>()
+                );
+                for (a, b) in row.iter().tuple_windows() {
+                    *followers.entry((a, b)).or_insert(0) += 1;
+                }
+            }
+            // every ordered pair of different items is adjacent, equally often
+            assert_eq!(followers.len(), n * (n - 1));
+            assert!(followers.values().all_equal());
+        }
+    }
+}
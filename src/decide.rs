@@ -1,27 +1,51 @@
-use super::{CorrectChoices, Error, Stimulus, StimulusAttribute};
+use super::{
+    parameters::with_decide_defaults, CorrectChoices, Error, Provenance, Stimulus,
+    StimulusAttribute,
+};
 use anyhow::Context;
 use fixed::traits::ToFixed;
 use fixed::types::I20F12;
 use fixed_macro::fixed;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
 use serde_value::Value;
 use serde_with::skip_serializing_none;
-use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    fs::File,
-    path::PathBuf,
-};
-use strum::{EnumIter, IntoEnumIterator};
+use std::{collections::BTreeMap, fs::File, path::PathBuf};
+use strum::{EnumIter, EnumString, EnumVariantNames, IntoEnumIterator};
 
-#[derive(Serialize, Deserialize, SerdeDiff, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, SerdeDiff, Clone)]
 #[serde(from = "LiteralDecideConfig")]
 #[serde(into = "LiteralDecideConfig")]
 pub struct DecideConfig {
     #[serde_diff(opaque)]
     parameters: Value,
     stimulus_root: PathBuf,
-    stimuli: HashMap<String, StimulusConfig>,
+    stimuli: BTreeMap<String, StimulusConfig>,
+    #[serde_diff(skip)]
+    provenance: Option<Provenance>,
+}
+
+/// Configs are equal if decide would run them the same way, wherever they came from,
+/// so a parameter left to decide's default equals one set to the same value
+impl PartialEq for DecideConfig {
+    fn eq(&self, other: &Self) -> bool {
+        with_decide_defaults(&self.parameters) == with_decide_defaults(&other.parameters)
+            && self.stimulus_root == other.stimulus_root
+            && self.stimuli == other.stimuli
+    }
+}
+
+impl Eq for DecideConfig {}
+
+impl JsonSchema for DecideConfig {
+    fn schema_name() -> String {
+        LiteralDecideConfig::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        LiteralDecideConfig::json_schema(gen)
+    }
 }
 
 impl From<DecideConfig> for LiteralDecideConfig {
@@ -30,6 +54,7 @@ impl From<DecideConfig> for LiteralDecideConfig {
             parameters,
             stimulus_root,
             stimuli,
+            provenance,
         }: DecideConfig,
     ) -> Self {
         let stimuli = stimuli.into_values().collect();
@@ -37,6 +62,7 @@ impl From<DecideConfig> for LiteralDecideConfig {
             parameters,
             stimulus_root,
             stimuli,
+            provenance,
         }
     }
 }
@@ -47,6 +73,7 @@ impl From<LiteralDecideConfig> for DecideConfig {
             parameters,
             stimulus_root,
             stimuli,
+            provenance,
         }: LiteralDecideConfig,
     ) -> Self {
         let stimuli = stimuli.into_iter().map(|v| (v.name.clone(), v)).collect();
@@ -54,15 +81,21 @@ impl From<LiteralDecideConfig> for DecideConfig {
             parameters,
             stimulus_root,
             stimuli,
+            provenance,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[schemars(rename = "DecideConfig")]
 struct LiteralDecideConfig {
+    #[schemars(with = "serde_json::Map<String, serde_json::Value>")]
     parameters: Value,
     stimulus_root: PathBuf,
-    stimuli: HashSet<StimulusConfig>,
+    /// sorted by name
+    stimuli: Vec<StimulusConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
 }
 
 impl DecideConfig {
@@ -75,21 +108,94 @@ impl DecideConfig {
             stimuli,
             stimulus_root,
             parameters,
+            provenance: None,
         }
         .into()
     }
 
+    /// Records where the config came from in a `provenance` block
+    pub fn with_provenance(self, provenance: Provenance) -> Self {
+        DecideConfig {
+            provenance: Some(provenance),
+            ..self
+        }
+    }
+
+    /// The config with decide's defaults filled in for the known parameters it leaves
+    /// out, so that comparing it shows only the differences decide would see
+    pub fn with_decide_defaults(self) -> Self {
+        DecideConfig {
+            parameters: with_decide_defaults(&self.parameters),
+            ..self
+        }
+    }
+
+    pub fn parameters(&self) -> &Value {
+        &self.parameters
+    }
+
+    pub fn stimulus_root(&self) -> &PathBuf {
+        &self.stimulus_root
+    }
+
+    pub fn stimuli(&self) -> impl Iterator<Item = &StimulusConfig> {
+        self.stimuli.values()
+    }
+
     pub fn to_json(&self, config_name: String) -> anyhow::Result<()> {
-        let config_file = File::create(&config_name)
+        self.write(&config_name, ConfigFormat::Json)
+    }
+
+    pub fn write(&self, config_name: &str, format: ConfigFormat) -> anyhow::Result<()> {
+        let config_file = File::create(config_name)
             .with_context(|| format!("could not create config `{}`", config_name))?;
-        serde_json::to_writer_pretty(config_file, &self)
-            .with_context(|| format!("could not write config `{}`", config_name))?;
-        Ok(())
+        match format {
+            ConfigFormat::Json => {
+                serde_json::to_writer_pretty(config_file, &self).map_err(anyhow::Error::from)
+            }
+            ConfigFormat::Yaml => {
+                serde_yaml::to_writer(config_file, &self).map_err(anyhow::Error::from)
+            }
+            ConfigFormat::CanonicalJson => {
+                serde_json::to_writer(config_file, &self.canonical()).map_err(anyhow::Error::from)
+            }
+        }
+        .with_context(|| format!("could not write config `{}`", config_name))
+    }
+
+    /// The config with its keys in alphabetical order and its stimuli sorted by name, so
+    /// that equal configs are written the same way
+    fn canonical(&self) -> serde_json::Value {
+        let mut config = serde_json::to_value(self).expect("configs can always be serialized");
+        if let Some(stimuli) = config["stimuli"].as_array_mut() {
+            stimuli.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        }
+        config
+    }
+}
+
+/// How configs are written for decide
+#[derive(EnumString, EnumVariantNames, PartialEq, Clone, Copy, Debug)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ConfigFormat {
+    /// indented JSON
+    Json,
+    Yaml,
+    /// JSON without whitespace, with keys and stimuli in a fixed order
+    CanonicalJson,
+}
+
+impl ConfigFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConfigFormat::Json | ConfigFormat::CanonicalJson => "json",
+            ConfigFormat::Yaml => "yml",
+        }
     }
 }
 
 #[skip_serializing_none]
-#[derive(Serialize, Deserialize, SerdeDiff, Clone, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, SerdeDiff, JsonSchema, Clone, PartialEq, Eq, Hash)]
 pub struct StimulusConfig {
     name: String,
     frequency: u32,
@@ -118,12 +224,33 @@ impl StimulusConfig {
             responses,
         })
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    pub fn category(&self) -> Option<&StimulusAttribute> {
+        self.category.as_ref()
+    }
+
+    /// The response that is marked correct for this stimulus, if there is one
+    pub fn correct_response(&self) -> Option<Response> {
+        self.responses
+            .iter()
+            .find(|(_, outcome)| outcome.correct)
+            .map(|(response, _)| *response)
+    }
 }
 
 #[derive(
     Deserialize,
     Serialize,
     SerdeDiff,
+    JsonSchema,
     PartialEq,
     Eq,
     Clone,
@@ -168,9 +295,11 @@ impl From<Decimal> for f64 {
 }
 
 #[skip_serializing_none]
-#[derive(Serialize, Deserialize, SerdeDiff, PartialEq, Eq, Hash, Clone)]
+#[derive(Serialize, Deserialize, SerdeDiff, JsonSchema, PartialEq, Eq, Hash, Clone)]
 struct Outcome {
+    #[schemars(with = "Option<f64>")]
     p_reward: Option<Decimal>,
+    #[schemars(with = "Option<f64>")]
     p_punish: Option<Decimal>,
     correct: bool,
 }
@@ -226,4 +355,32 @@ mod tests {
         let f: Decimal = Decimal(fixed!(1.0: I20F12));
         assert_eq!(serde_json::to_string(&f).unwrap(), "1.0");
     }
+
+    #[test]
+    fn canonical_config() {
+        let config: DecideConfig = serde_json::from_str(
+            r#"{"stimulus_root": "/", "parameters": {"b": 1, "a": 2}, "stimuli": [
+                {"name": "y", "frequency": 1, "responses": {}},
+                {"name": "x", "frequency": 1, "responses": {}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&config.canonical()).unwrap(),
+            r#"{"parameters":{"a":2,"b":1},"stimuli":[{"frequency":1,"name":"x","responses":{}},{"frequency":1,"name":"y","responses":{}}],"stimulus_root":"/"}"#
+        );
+    }
+
+    #[test]
+    fn parameters_left_to_defaults() {
+        let config = |parameters| {
+            serde_json::from_str::<DecideConfig>(&format!(
+                r#"{{"stimulus_root": "/", "parameters": {}, "stimuli": []}}"#,
+                parameters
+            ))
+            .unwrap()
+        };
+        assert!(config(r#"{"max_corrections": 10}"#) == config("{}"));
+        assert!(config(r#"{"max_corrections": 5}"#) != config("{}"));
+    }
 }
@@ -0,0 +1,255 @@
+use super::{stimulus::AttributeMatch, AttributeLabel, Error, StimulusAttribute};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::Deserialize;
+use std::{collections::HashMap, convert::TryFrom, fmt, iter::Peekable, str::Chars};
+
+/// An attribute computed from the other attributes of each stimulus,
+/// either with an arithmetic expression or with a lookup table
+#[derive(Deserialize, JsonSchema, Debug)]
+pub struct DerivedAttribute {
+    expr: Option<Expression>,
+    lookup: Option<AttributeLabel>,
+    #[serde(default)]
+    map: HashMap<StimulusAttribute, StimulusAttribute>,
+    default: Option<StimulusAttribute>,
+    #[serde(rename = "match", default)]
+    pub matching: Option<AttributeMatch>,
+}
+
+impl DerivedAttribute {
+    /// The attributes this one is computed from
+    pub fn references(&self) -> Vec<&AttributeLabel> {
+        let mut references = Vec::new();
+        if let Some(expr) = self.expr.as_ref() {
+            expr.references(&mut references);
+        }
+        references.extend(self.lookup.iter());
+        references
+    }
+
+    pub fn validate(&self, label: &AttributeLabel) -> Result<(), Error> {
+        match (&self.expr, &self.lookup) {
+            (Some(_), None) if self.map.is_empty() && self.default.is_none() => Ok(()),
+            (None, Some(_)) => Ok(()),
+            _ => Err(Error::InvalidDerivation(label.to_string())),
+        }
+    }
+
+    pub fn evaluate(
+        &self,
+        label: &AttributeLabel,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+    ) -> Result<StimulusAttribute, Error> {
+        if let Some(expr) = self.expr.as_ref() {
+            return expr
+                .evaluate(attributes)
+                .map(StimulusAttribute::from)
+                .map_err(|reason| Error::DerivedEvaluation {
+                    label: label.to_string(),
+                    reason,
+                });
+        }
+        let key = self
+            .lookup
+            .as_ref()
+            .and_then(|lookup| attributes.get(lookup))
+            .expect("lookup attribute is missing from stimulus");
+        self.map
+            .get(key)
+            .or(self.default.as_ref())
+            .cloned()
+            .ok_or_else(|| Error::DerivedEvaluation {
+                label: label.to_string(),
+                reason: format!("`{}` is not in `map` and there is no `default`", key),
+            })
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(try_from = "String")]
+pub enum Expression {
+    Number(i32),
+    Attribute(AttributeLabel),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+/// Written as a string such as `fg_db - bg_db`
+impl JsonSchema for Expression {
+    fn schema_name() -> String {
+        String::from("Expression")
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Expression {
+    fn references<'a>(&'a self, references: &mut Vec<&'a AttributeLabel>) {
+        match self {
+            Expression::Number(_) => {}
+            Expression::Attribute(label) => references.push(label),
+            Expression::Negate(expr) => expr.references(references),
+            Expression::Binary(lhs, _, rhs) => {
+                lhs.references(references);
+                rhs.references(references);
+            }
+        }
+    }
+
+    fn evaluate(
+        &self,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+    ) -> Result<i32, String> {
+        match self {
+            Expression::Number(num) => Ok(*num),
+            Expression::Attribute(label) => attributes
+                .get(label)
+                .and_then(StimulusAttribute::as_numeric)
+                .ok_or_else(|| format!("{} is not a number", label)),
+            Expression::Negate(expr) => Ok(-expr.evaluate(attributes)?),
+            Expression::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(attributes)?, rhs.evaluate(attributes)?);
+                match op {
+                    Operator::Add => lhs.checked_add(rhs),
+                    Operator::Subtract => lhs.checked_sub(rhs),
+                    Operator::Multiply => lhs.checked_mul(rhs),
+                    Operator::Divide => lhs.checked_div(rhs),
+                }
+                .ok_or_else(|| format!("{} {} {} is out of range", lhs, op, rhs))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+        let expr = parser.sum().and_then(|expr| match parser.next_token() {
+            None => Ok(expr),
+            Some(c) => Err(format!("unexpected `{}`", c)),
+        });
+        expr.map_err(|reason| Error::InvalidExpression(source.clone(), reason))
+    }
+}
+
+/// Recursive descent parser for `+ - * /` over integers and attribute names
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn next_token(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut expr = self.product()?;
+        while let Some(op) = match self.next_token() {
+            Some('+') => Some(Operator::Add),
+            Some('-') => Some(Operator::Subtract),
+            _ => None,
+        } {
+            self.chars.next();
+            expr = Expression::Binary(Box::new(expr), op, Box::new(self.product()?));
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut expr = self.term()?;
+        while let Some(op) = match self.next_token() {
+            Some('*') => Some(Operator::Multiply),
+            Some('/') => Some(Operator::Divide),
+            _ => None,
+        } {
+            self.chars.next();
+            expr = Expression::Binary(Box::new(expr), op, Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expression, String> {
+        match self.next_token() {
+            Some('(') => {
+                self.chars.next();
+                let expr = self.sum()?;
+                match self.next_token() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => Err(String::from("missing `)`")),
+                }
+            }
+            Some('-') => {
+                self.chars.next();
+                Ok(Expression::Negate(Box::new(self.term()?)))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take_while(|c| c.is_ascii_digit());
+                digits
+                    .parse()
+                    .map(Expression::Number)
+                    .map_err(|_| format!("{} is out of range", digits))
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                Ok(Expression::Attribute(AttributeLabel::from(&name[..])))
+            }
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.chars.next_if(|&c| predicate(c)) {
+            taken.push(c);
+        }
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expression() {
+        let expr = Expression::try_from(String::from("fg_db - (bg_db + 2) * -3")).unwrap();
+        let attributes: HashMap<_, _> = vec![("fg_db", 70), ("bg_db", 50)]
+            .into_iter()
+            .map(|(k, v)| (AttributeLabel::from(k), StimulusAttribute::from(v)))
+            .collect();
+        assert_eq!(expr.evaluate(&attributes), Ok(70 + 52 * 3));
+        assert!(Expression::try_from(String::from("fg_db -")).is_err());
+        assert!(Expression::try_from(String::from("(fg_db")).is_err());
+    }
+}
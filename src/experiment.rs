@@ -1,11 +1,18 @@
 use super::{
-    stimulus::StimuliConfig, AttributeLabel, Error, Response, Stimulus, StimulusAttribute,
+    format_args,
+    parameters::Parameters,
+    stimulus::{StimuliConfig, UnvalidatedStimuliConfig},
+    subsets::{check_subset_definitions, resolve_subsets, SubsetDefinition},
+    validate::Problem,
+    AttributeLabel, DecisiveValue, Error, NameFormat, Response, SequenceConfig, SplitConfig,
+    StaircaseConfig, Stimulus, StimulusAttribute,
 };
-use dynfmt::{curly::SimpleCurlyFormat, Format};
+use itertools::Itertools;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::Deserialize;
 use serde_value::Value;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryFrom,
     path::PathBuf,
 };
@@ -22,6 +29,19 @@ impl Experiment {
         self.stimuli.stimuli()
     }
 
+    /// The values of the decisive attributes that appear in at least one stimulus
+    pub fn decisive_values(&self) -> Vec<DecisiveValue> {
+        self.stimuli()
+            .iter()
+            .map(Stimulus::decisive_value)
+            .unique()
+            .collect()
+    }
+
+    pub fn filter_report(&self) -> Vec<(String, usize)> {
+        self.stimuli.filter_report()
+    }
+
     pub fn named_args(&self) -> Result<Vec<AttributeLabel>, Error> {
         let args = self.decide.named_args()?;
         args.into_iter()
@@ -29,7 +49,7 @@ impl Experiment {
                 self.stimuli
                     .label_by_str(name)
                     .ok_or_else(|| Error::UnknownAttributeInNameFormat(String::from(name)))
-                    .map(|label| label.clone())
+                    .cloned()
             })
             .collect()
     }
@@ -38,24 +58,59 @@ impl Experiment {
         &self.decide.name_format
     }
 
-    pub fn stimuli_subsets(&self) -> Vec<(String, Vec<Stimulus<'_>>)> {
-        self.decide
-            .stimuli_subsets
-            .as_ref()
-            .map(|subsets| {
-                subsets
-                    .iter()
-                    .map(|(name, attribute_set)| {
-                        let set = self
-                            .stimuli()
-                            .into_iter()
-                            .filter(|name| attribute_set.contains(name.decisive_attribute()))
-                            .collect();
-                        (name.clone(), set)
+    pub fn stimuli_subsets(&self) -> Result<Vec<(String, Vec<Stimulus<'_>>)>, Error> {
+        match self.decide.stimuli_subsets.as_ref() {
+            Some(StimuliSubsets::Listed(subsets)) => {
+                let stimuli = self.stimuli();
+                Ok(resolve_subsets(subsets, &self.stimuli)?
+                    .into_iter()
+                    .map(|(name, indices)| {
+                        let set = indices.into_iter().map(|i| stimuli[i].clone()).collect();
+                        (name, set)
                     })
-                    .collect()
-            })
-            .unwrap_or_else(|| vec![(String::from("All"), self.stimuli())])
+                    .collect())
+            }
+            Some(StimuliSubsets::Generated(_)) => Err(Error::UnresolvedSubsets),
+            None => Ok(vec![(String::from("All"), self.stimuli())]),
+        }
+    }
+
+    /// The settings for generating `stimuli_subsets`, if they are not listed
+    pub fn subset_generator(&self) -> Option<&SplitConfig> {
+        match self.decide.stimuli_subsets.as_ref() {
+            Some(StimuliSubsets::Generated(split)) => Some(split),
+            _ => None,
+        }
+    }
+
+    /// Replaces generated `stimuli_subsets` with the subsets they generated
+    pub fn set_subsets<I>(&mut self, subsets: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, Vec<DecisiveValue>)>,
+    {
+        let mut subsets = StimuliSubsets::Listed(
+            subsets
+                .into_iter()
+                .map(|(name, values)| (name, SubsetDefinition::Values(values)))
+                .collect(),
+        );
+        let mut problems = split_subset_values(&mut subsets, self.decisive_attributes().len());
+        problems.extend(subset_problems(&self.stimuli, &subsets));
+        if let Some(problem) = problems.into_iter().next() {
+            return Err(problem.error);
+        }
+        self.decide.stimuli_subsets = Some(subsets);
+        Ok(())
+    }
+
+    /// The settings for generating a trial sequence alongside each config, if any
+    pub fn trial_sequence(&self) -> Option<&SequenceConfig> {
+        self.decide.trial_sequence.as_ref()
+    }
+
+    /// The settings for stepping through the values of an attribute, if any
+    pub fn staircase(&self) -> Option<&StaircaseConfig> {
+        self.decide.staircase.as_ref()
     }
 
     pub fn attribute_labels(&self) -> impl Iterator<Item = &AttributeLabel> {
@@ -66,8 +121,42 @@ impl Experiment {
         self.stimuli.list_values(label)
     }
 
-    pub fn decide_parameters(&self) -> &Value {
-        &self.decide.parameters
+    /// The parameters for a config of the subset `set` with `attributes`, with any
+    /// overrides for the set and with the templates filled in
+    pub fn decide_parameters(
+        &self,
+        set: &str,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+    ) -> Result<Value, Error> {
+        let pass_through =
+            Some(&self.decide.extra_parameters[..]).filter(|_| self.decide.drop_unknown_parameters);
+        match (&self.decide.parameters, self.decide.set_parameters.get(set)) {
+            (Some(parameters), Some(overrides)) => {
+                parameters.merge(overrides).render(attributes, pass_through)
+            }
+            (Some(parameters), None) => parameters.render(attributes, pass_through),
+            (None, Some(overrides)) => overrides.render(attributes, pass_through),
+            (None, None) => Ok(Value::Unit),
+        }
+    }
+
+    /// Whether unknown keys in `parameters` are left out of the configs
+    pub fn drops_unknown_parameters(&self) -> bool {
+        self.decide.drop_unknown_parameters
+    }
+
+    /// Keys in `parameters` and `set_parameters` that decide is not known to use and
+    /// that are not listed in `extra_parameters`
+    pub fn unknown_parameters(&self) -> Vec<&str> {
+        let pass_through = &self.decide.extra_parameters;
+        self.decide
+            .parameters
+            .iter()
+            .chain(self.decide.set_parameters.values())
+            .flat_map(|parameters| parameters.unknown_keys(pass_through))
+            .unique()
+            .sorted()
+            .collect()
     }
 
     pub fn stimulus_root(&self) -> &PathBuf {
@@ -78,59 +167,195 @@ impl Experiment {
         vec![self.decide.choices.0, self.decide.choices.1]
     }
 
-    pub fn decisive_attribute(&self) -> &AttributeLabel {
-        self.stimuli.decisive_attribute()
+    pub fn decisive_attributes(&self) -> &[AttributeLabel] {
+        self.stimuli.decisive_attributes()
+    }
+
+    /// The stimuli `format`, for reading attributes out of stimulus names such as the
+    /// ones in trial logs
+    pub fn stimulus_name_format(&self) -> Result<NameFormat, Error> {
+        NameFormat::new(self.stimuli.format())
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct ExperimentConfig {
-    pub parameters: Value,
+    /// an empty `parameters:` is written to the configs as it is
+    #[serde(deserialize_with = "Option::deserialize")]
+    pub parameters: Option<Parameters>,
+    /// keys in `parameters` to pass on to decide without a warning, even though they
+    /// are not known
+    #[serde(default)]
+    pub extra_parameters: Vec<String>,
+    /// whether to leave unknown keys that are not in `extra_parameters` out of the configs
+    #[serde(default)]
+    pub drop_unknown_parameters: bool,
+    /// parameters that are different for some stimuli subsets
+    #[serde(default)]
+    pub set_parameters: BTreeMap<String, Parameters>,
     pub name_format: String,
     pub stimulus_root: PathBuf,
     pub choices: (Response, Response),
-    pub stimuli_subsets: Option<HashMap<String, Vec<StimulusAttribute>>>,
+    pub stimuli_subsets: Option<StimuliSubsets>,
+    pub trial_sequence: Option<SequenceConfig>,
+    pub staircase: Option<StaircaseConfig>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum StimuliSubsets {
+    Generated(SplitConfig),
+    Listed(BTreeMap<String, SubsetDefinition>),
 }
 
 impl ExperimentConfig {
     pub fn named_args(&self) -> Result<Vec<&str>, Error> {
-        SimpleCurlyFormat
-            .iter_args(&self.name_format)
-            .map_err(|_| Error::Format)?
-            .map(|arg_spec| {
-                let arg_spec = arg_spec.map_err(|_| Error::Format)?;
-                Ok(&self.name_format[arg_spec.start() + 1..arg_spec.end() - 1])
-            })
-            .collect()
+        format_args(&self.name_format)
     }
 }
 
-#[derive(Deserialize)]
-struct UnvalidatedExperiment {
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "Experiment")]
+pub struct UnvalidatedExperiment {
     decide: ExperimentConfig,
-    stimuli: StimuliConfig,
+    #[schemars(with = "StimuliConfig")]
+    stimuli: UnvalidatedStimuliConfig,
+}
+
+impl JsonSchema for Experiment {
+    fn schema_name() -> String {
+        UnvalidatedExperiment::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        UnvalidatedExperiment::json_schema(gen)
+    }
 }
 
 impl TryFrom<UnvalidatedExperiment> for Experiment {
     type Error = Error;
 
-    fn try_from(
-        UnvalidatedExperiment { decide, stimuli }: UnvalidatedExperiment,
-    ) -> Result<Self, Self::Error> {
-        let all_values: HashSet<_> = stimuli
-            .list_values(stimuli.decisive_attribute())
-            .ok_or(Error::DecisiveAttributeNotFound)?
-            .into_iter()
-            .collect();
-        if let Some(stimuli_subsets) = decide.stimuli_subsets.as_ref() {
-            for (name, subset) in stimuli_subsets.iter() {
-                let subset: HashSet<_> = subset.iter().collect();
-                if !subset.is_subset(&all_values) {
-                    return Err(Error::NotASubset(name.clone()));
+    fn try_from(experiment: UnvalidatedExperiment) -> Result<Self, Self::Error> {
+        experiment
+            .check()
+            .map_err(|problems| problems.into_iter().next().unwrap().error)
+    }
+}
+
+impl UnvalidatedExperiment {
+    /// Builds the experiment, or lists every problem found with it
+    pub fn check(self) -> Result<Experiment, Vec<Problem>> {
+        let UnvalidatedExperiment {
+            mut decide,
+            stimuli,
+        } = self;
+        let mut problems = Vec::new();
+        if decide.choices.0 == decide.choices.1 {
+            problems.push(Problem::new("decide.choices", Error::IdenticalChoices));
+        }
+        let stimuli = match stimuli.build() {
+            Ok(stimuli) => stimuli,
+            Err(problem) => {
+                problems.push(problem);
+                return Err(problems);
+            }
+        };
+        let split_problems = match decide.stimuli_subsets.as_mut() {
+            Some(subsets) => split_subset_values(subsets, stimuli.decisive_attributes().len()),
+            None => Vec::new(),
+        };
+        if stimuli
+            .decisive_attributes()
+            .iter()
+            .any(|label| stimuli.list_values(label).is_none())
+        {
+            problems.push(Problem::new(
+                "stimuli.decisive_attribute",
+                Error::DecisiveAttributeNotFound,
+            ));
+            return Err(problems);
+        }
+        if let Ok(args) = decide.named_args() {
+            let placeholders: Vec<&str> = args.into_iter().chain(vec!["set", "inverted"]).collect();
+            if let Some(parameters) = &decide.parameters {
+                problems.extend(parameters.problems("decide.parameters", &placeholders));
+            }
+            for (set, overrides) in decide.set_parameters.iter().sorted_by_key(|(set, _)| *set) {
+                let path = format!("decide.set_parameters.{}", set);
+                problems.extend(overrides.problems(&path, &placeholders));
+            }
+        }
+        problems.extend(stimuli.problems());
+        match decide.named_args() {
+            Ok(args) => problems.extend(
+                args.into_iter()
+                    .filter(|arg| stimuli.label_by_str(arg).is_none())
+                    .map(|arg| {
+                        let error = Error::UnknownAttributeInNameFormat(arg.into());
+                        Problem::new("decide.name_format", error)
+                    }),
+            ),
+            Err(e) => problems.push(Problem::new("decide.name_format", e)),
+        }
+        if !split_problems.is_empty() {
+            problems.extend(split_problems);
+        } else if let Some(stimuli_subsets) = decide.stimuli_subsets.as_ref() {
+            problems.extend(subset_problems(&stimuli, stimuli_subsets));
+        }
+        if let Some(Err(e)) = decide.trial_sequence.as_ref().map(SequenceConfig::validate) {
+            problems.push(Problem::new("decide.trial_sequence", e));
+        }
+        if let (Some(staircase), Ok(args)) = (decide.staircase.as_ref(), decide.named_args()) {
+            if let Err(e) = staircase.validate(&stimuli, &args) {
+                problems.push(Problem::new("decide.staircase", e));
+            }
+        }
+        if problems.is_empty() {
+            Ok(Experiment { decide, stimuli })
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// Reads listed decisive values written as one string for several decisive attributes,
+/// with a problem for each value that does not have one part for each of them
+fn split_subset_values(subsets: &mut StimuliSubsets, n_attributes: usize) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if let StimuliSubsets::Listed(subsets) = subsets {
+        for (name, definition) in subsets.iter_mut() {
+            if let SubsetDefinition::Values(values) = definition {
+                for (i, value) in values.iter_mut().enumerate() {
+                    match value.clone().split(n_attributes) {
+                        Ok(split) => *value = split,
+                        Err(e) => problems.push(Problem::new(
+                            format!("decide.stimuli_subsets.{}[{}]", name, i),
+                            e,
+                        )),
+                    }
                 }
             }
         }
-        Ok(Experiment { decide, stimuli })
+    }
+    problems
+}
+
+fn subset_problems(stimuli: &StimuliConfig, subsets: &StimuliSubsets) -> Vec<Problem> {
+    match subsets {
+        StimuliSubsets::Listed(subsets) => check_subset_definitions(subsets, stimuli),
+        StimuliSubsets::Generated(split) => {
+            let all_values: HashSet<_> = stimuli
+                .stimuli()
+                .iter()
+                .map(Stimulus::decisive_value)
+                .collect();
+            split
+                .validate(all_values.len())
+                .err()
+                .map(|e| Problem::new("decide.stimuli_subsets", e))
+                .into_iter()
+                .collect()
+        }
     }
 }
 
@@ -154,4 +379,60 @@ mod tests {
         let named_args = exp.named_args().unwrap();
         assert_eq!(named_args, vec!["a", "b", "c"]);
     }
+
+    #[test]
+    fn deterministic_configs() {
+        let configs = || {
+            let experiment: Experiment = crate::test_experiment(
+                "
+                parameters: {response_window: 2000, feed_duration: 500}
+                name_format: 'x{bg}'
+                stimuli_subsets: {one: [a, b, c], two: [d, e, f], three: [a, f]}
+                ",
+                "
+                format: '{fg}_{bg}_{db}'
+                decisive_attribute: fg
+                fg: {values: [a, b, c, d, e, f]}
+                bg: {values: [n1, n2, n3]}
+                db: {values: [30, 40, 50]}
+                ",
+            )
+            .unwrap();
+            let correct_choices = crate::CorrectChoices::random(&experiment, 1).unwrap();
+            let names: Vec<String> = experiment.stimuli().into_iter().map(String::from).collect();
+            let configs: Vec<String> = crate::make_configs(&experiment, &correct_choices)
+                .unwrap()
+                .into_iter()
+                .map(|(config, _)| serde_json::to_string(&config).unwrap())
+                .collect();
+            (names, configs)
+        };
+        let (names, first) = configs();
+        // attributes are combined in order of their labels, with the last changing fastest
+        assert_eq!(&names[..2], ["a_n1_30", "b_n1_30"]);
+        for _ in 0..5 {
+            assert_eq!(configs(), (names.clone(), first.clone()));
+        }
+    }
+
+    #[test]
+    fn subset_value_parts() {
+        let experiment: UnvalidatedExperiment = crate::test_experiment(
+            "{stimuli_subsets: {first: ['a, 30', 'a, 30, loud']}}",
+            "
+            format: '{fg}_{db}'
+            decisive_attribute: [fg, db]
+            fg: {values: [a, b]}
+            db: {values: [30]}
+            ",
+        )
+        .unwrap();
+        let problems = experiment.check().err().unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "decide.stimuli_subsets.first[1]");
+        assert!(matches!(
+            problems[0].error,
+            Error::DecisiveValueParts(_, 3, 2)
+        ));
+    }
 }
@@ -0,0 +1,400 @@
+use super::{
+    make_configs, parameters::Parameters, AttributeLabel, CorrectChoices, DecideConfig,
+    DecisiveValue, Error, Experiment, NameFormat, Response, StimulusAttribute,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_value::Value;
+use serde_with::skip_serializing_none;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+type AttributeRow = BTreeMap<AttributeLabel, StimulusAttribute>;
+type ParameterMap = BTreeMap<Value, Value>;
+
+/// An experiment file and correct choices reconstructed from existing configs
+pub struct ImportedExperiment {
+    /// the experiment file, as YAML
+    pub source: String,
+    pub experiment: Experiment,
+    pub correct_choices: CorrectChoices,
+}
+
+#[derive(Serialize)]
+struct ImportedFile {
+    decide: ImportedDecide,
+    stimuli: ImportedStimuli,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct ImportedDecide {
+    parameters: ParameterMap,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_parameters: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    set_parameters: BTreeMap<String, ParameterMap>,
+    name_format: String,
+    stimulus_root: PathBuf,
+    choices: (Response, Response),
+    stimuli_subsets: Option<BTreeMap<String, ImportedSubset>>,
+}
+
+/// A subset with the stimuli that have the values of each attribute in one config
+#[derive(Serialize)]
+struct ImportedSubset {
+    #[serde(rename = "where")]
+    filter: BTreeMap<AttributeLabel, Vec<StimulusAttribute>>,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct ImportedStimuli {
+    format: String,
+    decisive_attribute: Vec<AttributeLabel>,
+    category: Option<AttributeLabel>,
+    table: Option<Vec<AttributeRow>>,
+    #[serde(flatten)]
+    values: BTreeMap<AttributeLabel, ImportedValues>,
+}
+
+#[derive(Serialize)]
+struct ImportedValues {
+    values: Vec<StimulusAttribute>,
+}
+
+/// Reconstructs the experiment that `configs` were made from, reading the attributes
+/// of each stimulus from its name with the stimuli `format`. Each config becomes the
+/// stimuli subset named with its string, unless there is only one, with a `where` rule
+/// for the attributes that its stimuli have only some of the values of. Configs split
+/// by a held-constant attribute get a subset for each of its values.
+pub fn import_configs(
+    configs: &[(String, DecideConfig)],
+    format: &str,
+    decisive_attributes: &[AttributeLabel],
+    name_format: &str,
+) -> Result<ImportedExperiment, Error> {
+    let stimulus_names = NameFormat::new(format)?;
+    let labels: Vec<AttributeLabel> = stimulus_names.labels().into_iter().cloned().collect();
+    if let Some(label) = decisive_attributes.iter().find(|l| !labels.contains(l)) {
+        return Err(Error::InvalidImport(format!(
+            "the decisive attribute {} is not in the stimuli format",
+            label
+        )));
+    }
+    if configs.iter().map(|(set, _)| set).unique().count() != configs.len() {
+        return Err(Error::InvalidImport(String::from(
+            "two of the configs would make the same stimuli subset",
+        )));
+    }
+    let stimulus_root = configs
+        .iter()
+        .map(|(_, config)| config.stimulus_root())
+        .unique()
+        .exactly_one()
+        .map_err(|_| {
+            Error::InvalidImport(String::from("the configs use different stimulus roots"))
+        })?
+        .clone();
+
+    let mut rows: BTreeMap<String, AttributeRow> = BTreeMap::new();
+    let mut responses: BTreeMap<DecisiveValue, Response> = BTreeMap::new();
+    let mut categories: BTreeMap<String, StimulusAttribute> = BTreeMap::new();
+    let mut config_values = Vec::new();
+    for (set, config) in configs {
+        let mut values: BTreeMap<AttributeLabel, BTreeSet<StimulusAttribute>> = BTreeMap::new();
+        for stimulus in config.stimuli() {
+            let row: AttributeRow = stimulus_names.parse(stimulus.name())?.into_iter().collect();
+            let value: DecisiveValue = decisive_attributes
+                .iter()
+                .map(|label| row[label].clone())
+                .collect();
+            let response = stimulus.correct_response().ok_or_else(|| {
+                Error::InvalidImport(format!("{} has no correct response", stimulus.name()))
+            })?;
+            if *responses.entry(value.clone()).or_insert(response) != response {
+                return Err(Error::InvalidImport(format!(
+                    "stimuli with the decisive value {} have different correct responses",
+                    value
+                )));
+            }
+            if let Some(category) = stimulus.category() {
+                categories.insert(String::from(stimulus.name()), category.clone());
+            }
+            for (label, value) in row.iter() {
+                values
+                    .entry(label.clone())
+                    .or_default()
+                    .insert(value.clone());
+            }
+            rows.insert(String::from(stimulus.name()), row);
+        }
+        config_values.push((set.clone(), values));
+    }
+    let subsets = config_values
+        .into_iter()
+        .map(|(set, values)| {
+            let filter = values
+                .into_iter()
+                .filter(|(label, values)| rows.values().any(|row| !values.contains(&row[label])))
+                .map(|(label, values)| (label, values.into_iter().collect()))
+                .collect();
+            (set, ImportedSubset { filter })
+        })
+        .collect();
+    let used: Vec<Response> = responses.values().cloned().unique().sorted().collect();
+    let choices = match used[..] {
+        [first, second] => (first, second),
+        _ => {
+            return Err(Error::InvalidImport(format!(
+            "the configs have {} different correct responses, but an experiment has two choices",
+            used.len()
+        )))
+        }
+    };
+    // the category must be one of the attributes, with the same value for every stimulus
+    let category = if categories.is_empty() {
+        None
+    } else {
+        labels
+            .iter()
+            .find(|label| {
+                rows.iter()
+                    .all(|(name, row)| categories.get(name) == Some(&row[*label]))
+            })
+            .cloned()
+    };
+
+    let (parameters, set_parameters) = split_parameters(configs)?;
+    let extra_parameters = unknown_parameter_keys(&parameters, set_parameters.values())?;
+    let decide = ImportedDecide {
+        parameters,
+        extra_parameters,
+        set_parameters,
+        name_format: String::from(name_format),
+        stimulus_root,
+        choices,
+        stimuli_subsets: Some(subsets).filter(|_| configs.len() > 1),
+    };
+    let stimuli = stimuli_file(format, decisive_attributes, category, &labels, rows);
+    let source = serde_yaml::to_string(&ImportedFile { decide, stimuli })
+        .expect("imported experiments can always be serialized");
+    let experiment =
+        serde_yaml::from_str(&source).map_err(|e| Error::InvalidImport(e.to_string()))?;
+    Ok(ImportedExperiment {
+        source,
+        experiment,
+        correct_choices: responses.into_iter().collect(),
+    })
+}
+
+impl ImportedExperiment {
+    /// The configs the imported experiment makes that differ from the ones it was
+    /// imported from, named like the imported configs, along with what it makes instead
+    pub fn mismatches<'a>(
+        &self,
+        configs: &'a [(String, DecideConfig)],
+    ) -> Result<Vec<(&'a str, DecideConfig)>, Error> {
+        let regenerated: BTreeMap<String, DecideConfig> =
+            make_configs(&self.experiment, &self.correct_choices)?
+                .into_iter()
+                .filter(|(_, attributes)| attributes["inverted"] == StimulusAttribute::from("No"))
+                .map(|(config, attributes)| (attributes["set"].to_string(), written(&config)))
+                .collect();
+        let mismatches = configs
+            .iter()
+            .filter_map(|(set, config)| {
+                let regenerated = if configs.len() == 1 {
+                    regenerated.values().next()
+                } else {
+                    regenerated.get(set)
+                }
+                .expect("every imported config has a set");
+                Some((&set[..], regenerated.clone())).filter(|_| regenerated != config)
+            })
+            .collect();
+        Ok(mismatches)
+    }
+}
+
+/// The config as it is read back after being written, so that numbers compare equal
+/// to the ones in configs read from files
+fn written(config: &DecideConfig) -> DecideConfig {
+    let json = serde_json::to_string(config).expect("configs can always be serialized");
+    serde_json::from_str(&json).expect("written configs can always be read")
+}
+
+/// The parameters every config has in common, and the rest of each config's
+/// parameters for `set_parameters`
+fn split_parameters(
+    configs: &[(String, DecideConfig)],
+) -> Result<(ParameterMap, BTreeMap<String, ParameterMap>), Error> {
+    let maps = configs
+        .iter()
+        .map(|(set, config)| match config.parameters() {
+            Value::Map(parameters) => Ok((set, parameters.clone())),
+            Value::Unit => Ok((set, ParameterMap::new())),
+            _ => Err(Error::InvalidImport(format!(
+                "the parameters of {} are not a mapping",
+                set
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let common: ParameterMap = maps[0]
+        .1
+        .iter()
+        .filter(|(key, value)| maps.iter().all(|(_, map)| map.get(key) == Some(value)))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let set_parameters = maps
+        .into_iter()
+        .map(|(set, map)| {
+            let overrides: ParameterMap = map
+                .iter()
+                .filter(|(key, _)| !common.contains_key(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            (set.clone(), overrides)
+        })
+        .filter(|(_, overrides)| !overrides.is_empty())
+        .collect();
+    Ok((common, set_parameters))
+}
+
+/// The keys of the parameters that decide is not known to use, which are listed in
+/// `extra_parameters` so that they are passed on without a warning
+fn unknown_parameter_keys<'a, I>(
+    parameters: &'a ParameterMap,
+    set_parameters: I,
+) -> Result<Vec<String>, Error>
+where
+    I: IntoIterator<Item = &'a ParameterMap>,
+{
+    let mut keys = Vec::new();
+    for map in std::iter::once(parameters).chain(set_parameters) {
+        let typed = Parameters::deserialize(Value::Map(map.clone()))
+            .map_err(|e| Error::InvalidImport(format!("invalid parameters: {}", e)))?;
+        keys.extend(typed.unknown_keys(&[]).map(String::from));
+    }
+    Ok(keys.into_iter().unique().sorted().collect())
+}
+
+/// Lists the values of each attribute if the stimuli are every combination of them,
+/// and lists the stimuli in a table otherwise
+fn stimuli_file(
+    format: &str,
+    decisive_attributes: &[AttributeLabel],
+    category: Option<AttributeLabel>,
+    labels: &[AttributeLabel],
+    rows: BTreeMap<String, AttributeRow>,
+) -> ImportedStimuli {
+    let values: BTreeMap<AttributeLabel, Vec<StimulusAttribute>> = labels
+        .iter()
+        .map(|label| {
+            let values = rows
+                .values()
+                .map(|row| row[label].clone())
+                .unique()
+                .sorted();
+            (label.clone(), values.collect())
+        })
+        .collect();
+    let combinations: usize = values.values().map(Vec::len).product();
+    let (table, values) = if combinations == rows.len() {
+        let values = values
+            .into_iter()
+            .map(|(label, values)| (label, ImportedValues { values }))
+            .collect();
+        (None, values)
+    } else {
+        (Some(rows.into_values().collect()), BTreeMap::new())
+    };
+    ImportedStimuli {
+        format: String::from(format),
+        decisive_attribute: decisive_attributes.to_vec(),
+        category,
+        table,
+        values,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn non_inverted_configs(
+        experiment: &Experiment,
+        correct_choices: &CorrectChoices,
+        name: &str,
+    ) -> Vec<(String, DecideConfig)> {
+        make_configs(experiment, correct_choices)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, attributes)| attributes["inverted"] == StimulusAttribute::from("No"))
+            .map(|(config, attributes)| (attributes[name].to_string(), written(&config)))
+            .collect()
+    }
+
+    #[test]
+    fn import_generated_configs() {
+        let experiment: Experiment = serde_yaml::from_str(
+            "
+            decide:
+                parameters: {response_window: 2000, stim_gap: 2}
+                extra_parameters: [stim_gap]
+                set_parameters: {b: {response_window: 3000}}
+                name_format: 'x'
+                stimulus_root: /
+                choices: [peck_left, peck_right]
+                stimuli_subsets: {a: [a1, a2], b: [a2]}
+            stimuli:
+                format: '{motif}_{fg_db}'
+                decisive_attribute: motif
+                motif:
+                    values: [a1, a2]
+                fg_db:
+                    values: [30, 40]
+            ",
+        )
+        .unwrap();
+        let correct_choices = CorrectChoices::random(&experiment, 1).unwrap();
+        let configs = non_inverted_configs(&experiment, &correct_choices, "set");
+        let motif = [AttributeLabel::from("motif")];
+        let imported = import_configs(&configs, "{motif}_{fg_db}", &motif, "x").unwrap();
+        assert!(imported.mismatches(&configs).unwrap().is_empty());
+        assert_eq!(imported.correct_choices.hash(), correct_choices.hash());
+        assert!(matches!(
+            import_configs(&configs, "{motif}-{fg_db}", &motif, "x"),
+            Err(Error::StimulusNameMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn import_configs_split_by_held_constant_attribute() {
+        let experiment: Experiment = serde_yaml::from_str(
+            "
+            decide:
+                parameters:
+                name_format: 'cfg-{fg_db}'
+                stimulus_root: /
+                choices: [peck_left, peck_right]
+            stimuli:
+                format: '{motif}_{fg_db}'
+                decisive_attribute: motif
+                motif:
+                    values: [a1, a2, a3]
+                fg_db:
+                    values: [30, 40]
+            ",
+        )
+        .unwrap();
+        let correct_choices = CorrectChoices::random(&experiment, 1).unwrap();
+        let configs = non_inverted_configs(&experiment, &correct_choices, "fg_db");
+        assert_eq!(configs.len(), 2);
+        let motif = [AttributeLabel::from("motif")];
+        let imported = import_configs(&configs, "{motif}_{fg_db}", &motif, "new").unwrap();
+        assert!(imported.mismatches(&configs).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+use super::Error;
+use serde_yaml::{Mapping, Value};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+use strum::{EnumString, EnumVariantNames};
+
+/// The languages an experiment file can be written in
+#[derive(EnumString, EnumVariantNames, PartialEq, Clone, Copy, Debug)]
+#[strum(serialize_all = "lowercase")]
+pub enum ExperimentFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ExperimentFormat {
+    /// The format of a file from its extension: YAML unless it ends in `.toml` or `.json`
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("toml") => ExperimentFormat::Toml,
+            Some("json") => ExperimentFormat::Json,
+            _ => ExperimentFormat::Yaml,
+        }
+    }
+
+    fn parse(self, path: &Path, source: &str) -> Result<Value, Error> {
+        let error = |e: String| Error::ExperimentFile(path.display().to_string(), e);
+        match self {
+            ExperimentFormat::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| error(e.to_string()))
+            }
+            ExperimentFormat::Toml => toml::from_str(source).map_err(|e| error(e.to_string())),
+            ExperimentFormat::Json => {
+                serde_json::from_str(source).map_err(|e| error(e.to_string()))
+            }
+        }
+    }
+}
+
+/// An experiment file with any `extends` and `include` files merged in
+pub struct ExperimentSources {
+    /// the merged experiment, as YAML
+    pub source: String,
+    /// whether `source` is different from the text of the experiment file
+    merged: bool,
+    /// every file that was read, in the order they were merged
+    files: Vec<SourceFile>,
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub format: ExperimentFormat,
+    pub text: String,
+    value: Value,
+}
+
+impl ExperimentSources {
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    /// The experiment file itself
+    pub fn name(&self) -> &str {
+        &self
+            .files
+            .last()
+            .expect("the experiment file is always read")
+            .name
+    }
+
+    /// The file that `path`, such as `decide.choices`, or its closest parent, was set
+    /// in. Later files override earlier ones, so it is the last file that has it.
+    pub fn origin(&self, path: &str) -> Option<&SourceFile> {
+        let mut path = path;
+        loop {
+            if let Some(file) = self.files.iter().rev().find(|f| contains(&f.value, path)) {
+                return Some(file);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+}
+
+/// Reads an experiment file in `format`, or the format of its extension, and returns
+/// it as YAML with any `extends` and `include` files merged in, and with the path of
+/// a CSV `stimuli.table` made relative to the file that names it. A YAML or JSON file
+/// that needs none of this is returned as it is, so that locations in it stay the same.
+pub fn experiment_source<P: AsRef<Path>>(
+    path: P,
+    format: Option<ExperimentFormat>,
+) -> Result<String, Error> {
+    Ok(experiment_sources(path, format)?.source)
+}
+
+/// Like `experiment_source`, along with the files that were merged
+pub fn experiment_sources<P: AsRef<Path>>(
+    path: P,
+    format: Option<ExperimentFormat>,
+) -> Result<ExperimentSources, Error> {
+    let path = path.as_ref();
+    let format = format.unwrap_or_else(|| ExperimentFormat::from_path(path));
+    let source = read(path)?;
+    let value = format.parse(path, &source)?;
+    // JSON is also YAML
+    if included_files(path, &value)?.is_empty()
+        && table_path(path, &value).is_none()
+        && format != ExperimentFormat::Toml
+    {
+        let file = SourceFile {
+            name: path.display().to_string(),
+            format,
+            text: source.clone(),
+            value,
+        };
+        Ok(ExperimentSources {
+            source,
+            merged: false,
+            files: vec![file],
+        })
+    } else {
+        let mut files = Vec::new();
+        let resolved = resolve(path, format, source, value, &mut Vec::new(), &mut files)?;
+        Ok(ExperimentSources {
+            source: serde_yaml::to_string(&resolved).expect("YAML values can always be serialized"),
+            merged: true,
+            files,
+        })
+    }
+}
+
+/// Whether `value` has the key or item at `path`, such as `stimuli.fg_db.values[2]`
+fn contains(value: &Value, path: &str) -> bool {
+    let mut value = value;
+    for part in path.split('.') {
+        let (key, indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+        value = match value.get(key) {
+            Some(value) => value,
+            None => return false,
+        };
+        for index in indices.split(']').filter(|index| !index.is_empty()) {
+            let found = index
+                .trim_start_matches('[')
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| value.get(index));
+            value = match found {
+                Some(value) => value,
+                None => return false,
+            };
+        }
+    }
+    true
+}
+
+/// Merges the file `extends` names, then each file `include` names in order, then
+/// `value` itself, adding each file to `files` in that order. Paths are relative to
+/// the file that names them.
+fn resolve(
+    path: &Path,
+    format: ExperimentFormat,
+    text: String,
+    mut value: Value,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<SourceFile>,
+) -> Result<Value, Error> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| Error::ExperimentFile(path.display().to_string(), e.to_string()))?;
+    if stack.contains(&canonical) {
+        return Err(Error::IncludeCycle(path.display().to_string()));
+    }
+    stack.push(canonical);
+    let mut resolved = Value::Mapping(Mapping::new());
+    for included in included_files(path, &value)? {
+        let source = read(&included)?;
+        let included_format = ExperimentFormat::from_path(&included);
+        let included_value = included_format.parse(&included, &source)?;
+        let included_value = resolve(
+            &included,
+            included_format,
+            source,
+            included_value,
+            stack,
+            files,
+        )?;
+        merge(&mut resolved, included_value, &[]);
+    }
+    if let Value::Mapping(mapping) = &mut value {
+        mapping.remove(&Value::from("extends"));
+        mapping.remove(&Value::from("include"));
+    }
+    if let Some(table) = table_path(path, &value) {
+        value["stimuli"]["table"] = Value::from(table);
+    }
+    files.push(SourceFile {
+        name: path.display().to_string(),
+        format,
+        text,
+        value: value.clone(),
+    });
+    merge(&mut resolved, value, &[]);
+    stack.pop();
+    Ok(resolved)
+}
+
+/// The files named by `extends` and `include`, with paths relative to `path`
+fn included_files(path: &Path, value: &Value) -> Result<Vec<PathBuf>, Error> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut files = Vec::new();
+    for key in ["extends", "include"].iter() {
+        let names: Vec<&Value> = match value.get(key) {
+            None => continue,
+            Some(Value::Sequence(names)) => names.iter().collect(),
+            Some(name) => vec![name],
+        };
+        for name in names {
+            let name = name.as_str().ok_or_else(|| {
+                Error::ExperimentFile(
+                    path.display().to_string(),
+                    format!("`{}` must be a file name or a list of file names", key),
+                )
+            })?;
+            files.push(directory.join(name));
+        }
+    }
+    Ok(files)
+}
+
+/// The path of the CSV file named by `stimuli.table`, relative to `path`, if that is
+/// not the path as it is written
+fn table_path(path: &Path, value: &Value) -> Option<String> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let table = Path::new(value.get("stimuli")?.get("table")?.as_str()?);
+    if directory.as_os_str().is_empty() || table.is_absolute() {
+        None
+    } else {
+        Some(directory.join(table).display().to_string())
+    }
+}
+
+/// Merges `overrides` into `base`, which is at `path` in the experiment. The file,
+/// its `decide` and `stimuli` sections, and the parameter mappings are merged key by
+/// key; anything else, such as an attribute or a stimuli subset, is replaced.
+fn merge(base: &mut Value, overrides: Value, path: &[&str]) {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) if merged_by_key(path) => {
+            for (key, value) in overrides {
+                match (base.get_mut(&key), key.as_str()) {
+                    (Some(existing), Some(name)) => {
+                        merge(existing, value, &[path, &[name]].concat())
+                    }
+                    _ => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn merged_by_key(path: &[&str]) -> bool {
+    matches!(
+        path,
+        [] | ["decide"]
+            | ["stimuli"]
+            | ["decide", "parameters"]
+            | ["decide", "set_parameters"]
+            | ["decide", "set_parameters", _]
+            | ["decide", "stimuli_subsets"]
+    )
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::ExperimentFile(path.display().to_string(), e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extends_and_include() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let directory = std::env::temp_dir().join(format!(
+            "decide-config-include-test-{}-{}",
+            std::process::id(),
+            nanos
+        ));
+        fs::create_dir_all(directory.join("base")).unwrap();
+        let write = |name: &str, source: &str| fs::write(directory.join(name), source).unwrap();
+        write(
+            "base/base.yml",
+            "
+            decide:
+                parameters: {response_window: 2000, feed_duration: 500}
+                stimuli_subsets: {train: {fg_db: [30]}, test: {fg_db: [40]}}
+            stimuli: {format: '{fg_db}', fg_db: {values: [30, 40], match: at_least}}
+            ",
+        );
+        write(
+            "base/choices.toml",
+            "[decide]\nchoices = ['peck_left', 'peck_right']",
+        );
+        write(
+            "experiment.yml",
+            "
+            extends: base/base.yml
+            include: [base/choices.toml]
+            decide:
+                parameters: {response_window: 3000}
+                stimuli_subsets: {train: {fg_db: [50]}}
+            stimuli: {fg_db: {values: [50]}}
+            ",
+        );
+        let resolved: Value = serde_yaml::from_str(
+            &experiment_source(directory.join("experiment.yml"), None).unwrap(),
+        )
+        .unwrap();
+        let expected: Value = serde_yaml::from_str(
+            "
+            decide:
+                parameters: {response_window: 3000, feed_duration: 500}
+                stimuli_subsets: {train: {fg_db: [50]}, test: {fg_db: [40]}}
+                choices: [peck_left, peck_right]
+            stimuli: {format: '{fg_db}', fg_db: {values: [50]}}
+            ",
+        )
+        .unwrap();
+        assert_eq!(resolved, expected);
+        let sources = experiment_sources(directory.join("experiment.yml"), None).unwrap();
+        let origin = |path| sources.origin(path).map(|file| file.name.clone()).unwrap();
+        assert!(origin("decide.parameters.feed_duration").ends_with("base.yml"));
+        assert!(origin("decide.parameters.response_window").ends_with("experiment.yml"));
+        assert!(origin("decide.choices[1]").ends_with("choices.toml"));
+        assert!(origin("stimuli.fg_db.values[0].missing").ends_with("experiment.yml"));
+        write("base/choices.toml", "include = '../experiment.yml'");
+        assert!(matches!(
+            experiment_source(directory.join("experiment.yml"), None),
+            Err(Error::IncludeCycle(_))
+        ));
+        write("base/table.yml", "stimuli: {table: stimuli.csv}");
+        let resolved: Value = serde_yaml::from_str(
+            &experiment_source(directory.join("base/table.yml"), None).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            resolved["stimuli"]["table"].as_str().map(PathBuf::from),
+            Some(directory.join("base").join("stimuli.csv"))
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
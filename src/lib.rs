@@ -1,45 +1,103 @@
 #[macro_use]
 extern crate log;
+use dynfmt::{curly::SimpleCurlyFormat, Format};
 use itertools::Itertools;
 use std::{collections::HashMap, iter};
 use thiserror::Error as ThisError;
 
+mod derived;
 mod stimulus;
-use stimulus::{AttributeLabel, Stimulus, StimulusAttribute};
+pub use stimulus::{AttributeLabel, AttributeMatch, StimulusAttribute};
+use stimulus::{DecisiveValue, Stimulus};
+
+mod names;
+pub use names::NameFormat;
 
 mod choices;
-pub use choices::CorrectChoices;
+pub use choices::{
+    experiment_hash, ChoicesDiff, ChoicesFile, CorrectChoices, VersionedChoices,
+    CHOICES_FORMAT_VERSION,
+};
 
 mod decide;
-pub use decide::{DecideConfig, Response, StimulusConfig};
+pub use decide::{ConfigFormat, DecideConfig, Response, StimulusConfig};
+
+mod parameters;
 
 mod experiment;
 pub use experiment::Experiment;
 
+mod include;
+pub use include::{experiment_source, experiment_sources, ExperimentFormat, ExperimentSources};
+
+mod import;
+pub use import::{import_configs, ImportedExperiment};
+
+mod subsets;
+pub use subsets::{SplitConfig, SubsetLock};
+
+mod subjects;
+pub use subjects::{Subject, SubjectRegistry};
+
+mod sequence;
+pub use sequence::{SequenceConfig, Trial, TrialSequence};
+
+mod staircase;
+pub use staircase::{LoggedTrial, StaircaseConfig, StaircaseState};
+
+mod provenance;
+pub use provenance::Provenance;
+
+mod cohort;
+pub use cohort::{balanced_latin_square, cohort_configs, ScheduleRow};
+
+mod validate;
+pub use validate::{validate, validate_sources, Diagnostic};
+
+mod schema;
+pub use schema::{schema, SchemaKind};
+
 pub type ConfigWithParams<'a> = (DecideConfig, HashMap<AttributeLabel, StimulusAttribute>);
-pub fn make_configs<'a, 'b>(
+pub fn make_configs<'a>(
+    experiment: &'a Experiment,
+    correct_choices: &CorrectChoices,
+) -> Result<Vec<ConfigWithParams<'a>>, Error> {
+    make_configs_at(experiment, correct_choices, &[])
+}
+
+/// Like `make_configs`, but only makes the configs where the held-constant
+/// attributes in `fixed` have the given values
+pub fn make_configs_at<'a>(
     experiment: &'a Experiment,
-    correct_choices: &'b CorrectChoices,
+    correct_choices: &CorrectChoices,
+    fixed: &[(AttributeLabel, StimulusAttribute)],
 ) -> Result<Vec<ConfigWithParams<'a>>, Error> {
     let inverted_choices = correct_choices.inverted();
     let format_arguments = experiment.named_args()?;
     trace!("named args: {:?}", format_arguments);
+    if let Some((label, _)) = fixed.iter().find(|(l, _)| !format_arguments.contains(l)) {
+        return Err(Error::UnknownAttributeInNameFormat(label.to_string()));
+    }
     info!("Starting config iteration");
-    debug_assert!(!experiment.stimuli_subsets().is_empty());
+    let stimuli_subsets = experiment.stimuli_subsets()?;
+    debug_assert!(!stimuli_subsets.is_empty());
     let mut held_constant_attributes = experiment
         .attribute_labels()
         .filter_map(|label| {
             if format_arguments.contains(label) {
-                experiment
-                    .list_attribute_values(label)
-                    .map(|values| iter::repeat(label).zip(values.into_iter()))
+                experiment.list_attribute_values(label).map(|values| {
+                    let values = values
+                        .into_iter()
+                        .filter(move |&value| fixed.iter().all(|(l, v)| l != label || v == value));
+                    iter::repeat(label).zip(values)
+                })
             } else {
                 None
             }
         })
         .multi_cartesian_product();
     itertools::iproduct!(
-        experiment.stimuli_subsets().into_iter(),
+        stimuli_subsets.into_iter(),
         iter::once(held_constant_attributes.next().unwrap_or_else(Vec::new))
             .chain(held_constant_attributes),
         vec![true, false]
@@ -60,9 +118,6 @@ pub fn make_configs<'a, 'b>(
             })
             .map(|stimulus| StimulusConfig::from(stimulus, correct))
             .collect::<Result<Vec<_>, _>>()?;
-        let parameters = experiment.decide_parameters().clone();
-        let stimulus_root = experiment.stimulus_root().clone();
-        let config = DecideConfig::new(stimuli, stimulus_root, parameters);
         let mut attributes: HashMap<_, _> = constant_attributes
             .into_iter()
             .map(|(k, v)| (k.clone(), v.clone()))
@@ -75,27 +130,156 @@ pub fn make_configs<'a, 'b>(
             AttributeLabel::from("inverted"),
             StimulusAttribute::from(if inverted { "Yes" } else { "No" }),
         );
+        let parameters = experiment.decide_parameters(&set_name, &attributes)?;
+        let stimulus_root = experiment.stimulus_root().clone();
+        let config = DecideConfig::new(stimuli, stimulus_root, parameters);
         Ok((config, attributes))
     })
     .collect()
 }
 
+/// The configs for one subject, made with its correct choices and only for its
+/// assigned inversion, with a `subject` attribute for naming them
+pub fn make_subject_configs<'a>(
+    experiment: &'a Experiment,
+    id: &str,
+    subject: &Subject,
+) -> Result<Vec<ConfigWithParams<'a>>, Error> {
+    let inverted = StimulusAttribute::from(if subject.inverted { "Yes" } else { "No" });
+    Ok(make_configs(experiment, &subject.correct_choices)?
+        .into_iter()
+        .filter(|(_, attributes)| attributes.get("inverted") == Some(&inverted))
+        .map(|(config, mut attributes)| {
+            attributes.insert(AttributeLabel::from("subject"), StimulusAttribute::from(id));
+            (config, attributes)
+        })
+        .collect())
+}
+
+/// The names of the `{placeholders}` in a format string
+fn format_args(format: &str) -> Result<Vec<&str>, Error> {
+    SimpleCurlyFormat
+        .iter_args(format)
+        .map_err(|_| Error::Format)?
+        .map(|arg_spec| {
+            let arg_spec = arg_spec.map_err(|_| Error::Format)?;
+            Ok(&format[arg_spec.start() + 1..arg_spec.end() - 1])
+        })
+        .collect()
+}
+
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("subset `{0}` contains unlisted attribute values")]
     NotASubset(String),
     #[error("the attribute {0} was used in `name_format`, but was not included under `stimuli`")]
     UnknownAttributeInNameFormat(String),
-    #[error("the attribute listed for `decisive_attribute` was not found in `stimuli`")]
+    #[error("an attribute listed for `decisive_attribute` was not found in `stimuli`")]
     DecisiveAttributeNotFound,
     #[error("an error occured while formating")]
     Format,
     #[error("could not find stimulus attribute {0} in correct choices file")]
-    StimMissingFromCorrectChoices(StimulusAttribute),
+    StimMissingFromCorrectChoices(DecisiveValue),
     #[error("the list of choices provided in the experiment file should not be empty")]
     EmptyChoices,
+    #[error("the attribute {0} sets both `match` and `inclusive_less_than`")]
+    ConflictingMatchRules(String),
+    #[error("the attribute {0} uses an ordered `match`, but not all of its values are numbers")]
+    NonNumericComparison(String),
+    #[error("the attribute {0} was used in an `exclude` or `include_only` rule, but was not included under `stimuli`")]
+    UnknownAttributeInRule(String),
+    #[error("no stimuli are left after applying the `exclude` and `include_only` rules")]
+    NoStimuli,
+    #[error("could not read stimulus table `{0}`: {1}")]
+    StimulusTable(String, csv::Error),
+    #[error("row {0} of the stimulus table has no value for {1}")]
+    IncompleteTableRow(usize, String),
+    #[error("the attribute {0} lists `values`, but the stimuli are listed in `table`")]
+    ValuesWithTable(String),
+    #[error("could not parse the expression `{0}`: {1}")]
+    InvalidExpression(String, String),
+    #[error("the derived attribute {0} needs either `expr`, or `lookup` with `map` and an optional `default`")]
+    InvalidDerivation(String),
+    #[error("the derived attribute {0} refers to {1}, which is not an attribute under `stimuli`")]
+    UnknownAttributeInDerived(String, String),
+    #[error("could not compute the derived attribute {label}: {reason}")]
+    DerivedEvaluation { label: String, reason: String },
+    #[error("the attribute {0} is defined more than once")]
+    DuplicateAttribute(String),
+    #[error("the decisive value `{0}` has {1} parts, but there are {2} decisive attributes")]
+    DecisiveValueParts(DecisiveValue, usize, usize),
+    #[error("invalid `stimuli_subsets` split: {0}")]
+    InvalidSplit(String),
+    #[error("`stimuli_subsets` are generated, but have not been generated yet")]
+    UnresolvedSubsets,
+    #[error("`stimuli_subsets` refers to an unknown subset `{0}`")]
+    UnknownSubset(String),
+    #[error("the subset `{0}` is part of a cycle of subsets defined by each other")]
+    SubsetCycle(String),
+    #[error("the subset `{0}` is empty")]
+    EmptySubset(String),
+    #[error("the attribute {0} was used in `format`, but was not included under `stimuli`")]
+    UnknownAttributeInFormat(String),
+    #[error("the attribute listed for `category` ({0}) was not found in `stimuli`")]
+    CategoryNotFound(String),
+    #[error("the value {1} is listed more than once for the attribute {0}")]
+    DuplicateValue(String, StimulusAttribute),
+    #[error("the two choices should be different")]
+    IdenticalChoices,
+    #[error("the stimuli {stimuli} would all be named `{name}`; add the attributes that tell them apart to `format`")]
+    StimulusNameCollision { name: String, stimuli: String },
+    #[error("the correct choices file has format version {0}, which is newer than this program can read")]
+    UnsupportedChoicesVersion(u32),
+    #[error("invalid `trial_sequence`: {0}")]
+    InvalidSequence(String),
+    #[error("invalid `staircase`: {0}")]
+    InvalidStaircase(String),
+    #[error("the trial log `{0}` has {1} trials, but {2} were already read from it")]
+    TrialLogShortened(String, usize, usize),
+    #[error("the parameter {0} {1}")]
+    InvalidParameter(String, String),
+    #[error("could not read experiment file `{0}`: {1}")]
+    ExperimentFile(String, String),
+    #[error("the experiment file `{0}` extends or includes itself")]
+    IncludeCycle(String),
+    #[error("the stimulus name `{0}` does not match the format `{1}`")]
+    StimulusNameMismatch(String, String),
+    #[error("the stimulus name `{name}` can be read with the format `{format}` as both {first} and {second}")]
+    AmbiguousStimulusName {
+        name: String,
+        format: String,
+        first: String,
+        second: String,
+    },
+    #[error("could not import the configs: {0}")]
+    InvalidImport(String),
 }
 
 #[doc = include_str!("../README.md")]
 #[cfg(doctest)]
 struct ReadmeDoctests;
+
+/// An experiment for tests with the `stimuli` section given, and with `decide` settings
+/// such as `stimuli_subsets` added to the ones every test experiment shares
+#[cfg(test)]
+fn test_experiment<T: serde::de::DeserializeOwned>(
+    decide: &str,
+    stimuli: &str,
+) -> Result<T, serde_yaml::Error> {
+    let mut experiment: serde_yaml::Value = serde_yaml::from_str(
+        "
+        decide:
+            parameters:
+            name_format: x
+            stimulus_root: /
+            choices: [peck_left, peck_right]
+        ",
+    )
+    .unwrap();
+    let decide: serde_yaml::Mapping = serde_yaml::from_str(decide).unwrap();
+    for (key, value) in decide {
+        experiment["decide"][key] = value;
+    }
+    experiment["stimuli"] = serde_yaml::from_str(stimuli).unwrap();
+    serde_yaml::from_value(experiment)
+}
@@ -1,16 +1,35 @@
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
-use std::{fs::File, io};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io,
+    path::Path,
+};
 #[macro_use]
 extern crate clap;
 use anyhow::{anyhow, Context, Result};
 use clap::ArgMatches;
-use decide_config::{CorrectChoices, DecideConfig, Error, Experiment};
-use dynfmt::{Format, SimpleCurlyFormat};
+use decide_config::{
+    balanced_latin_square, cohort_configs, AttributeLabel, ChoicesFile, ConfigFormat,
+    CorrectChoices, DecideConfig, Error, Experiment, ExperimentFormat, ExperimentSources,
+    LoggedTrial, Provenance, ScheduleRow, SchemaKind, StaircaseState, StimulusAttribute, Subject,
+    SubjectRegistry, SubsetLock, VersionedChoices,
+};
+use dynfmt::{Format, FormatArgs, SimpleCurlyFormat};
+use itertools::Itertools;
 use serde_diff::Diff;
+use strum::VariantNames;
 
 const DEFAULT_CORRECT_CHOICES_FILE: &str = "correct_choices.yml";
+const DEFAULT_SUBSETS_FILE: &str = "stimuli_subsets.yml";
+const DEFAULT_REGISTRY_FILE: &str = "subjects.yml";
+const DEFAULT_SCHEDULE_FILE: &str = "schedule.csv";
+const DEFAULT_STAIRCASE_FILE: &str = "staircase.yml";
+const FILE_FORMAT: &str = "-set{set}-inverted{inverted}";
+const SUBJECT_FILE_FORMAT: &str = "-subject{subject}-set{set}-inverted{inverted}";
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
@@ -18,10 +37,37 @@ fn main() -> Result<()> {
         "name for file with correct response for each stimulus [default: {}]",
         DEFAULT_CORRECT_CHOICES_FILE
     );
+    let subsets_help = &format!(
+        "name for file with generated stimuli subsets [default: {}]",
+        DEFAULT_SUBSETS_FILE
+    );
+    let registry_help = &format!(
+        "name for file with each subject's correct choices and inversion [default: {}]",
+        DEFAULT_REGISTRY_FILE
+    );
+    let schedule_help = &format!(
+        "name for the schedule of configs for each subject [default: {}]",
+        DEFAULT_SCHEDULE_FILE
+    );
+    let state_help = &format!(
+        "name for file with the staircase's level [default: {}]",
+        DEFAULT_STAIRCASE_FILE
+    );
     let matches = clap_app!(
     @app (app_from_crate!())
     (@arg experiment: [EXPERIMENT_YML] "yaml file containing stimuli, responses, and parameters")
+    (@arg format: --format [FORMAT] possible_values(ExperimentFormat::VARIANTS)
+        "language of the experiment file [default: from its extension, or yaml]")
+    (@arg output_format: --("output-format") [OUTPUT_FORMAT] possible_values(ConfigFormat::VARIANTS)
+        "how to write the configs [default: json]")
     (@arg correct: -c --("correct-choices") [CORRECT_YML] correct_choices_help)
+    (@arg subsets: -s --subsets [SUBSETS_YML] subsets_help)
+    (@arg registry: -r --registry [SUBJECTS_YML] registry_help)
+    (@arg subject: --subject [SUBJECT] "only make the configs for this subject, using the subject registry")
+    (@arg provenance: --provenance
+        "record the experiment, correct choices and attributes each config was made from in the config")
+    (@arg extend_choices: --("extend-choices")
+        "assign correct choices to decisive values missing from the correct choices file")
     (@subcommand diff =>
         (about: "compare two decide-config JSON output files")
         (@arg file1: <FILE1>)
@@ -30,67 +76,605 @@ fn main() -> Result<()> {
     (@subcommand stimuli =>
         (about: "print list of stimuli")
     )
+    (@subcommand validate =>
+        (about: "list every problem with the experiment file")
+    )
+    (@subcommand render =>
+        (about: "print the experiment file with any files it extends or includes merged in")
+    )
+    (@subcommand schema =>
+        (about: "print a JSON Schema for experiment files, their stimuli, configs or correct choices files")
+        (@arg kind: <KIND> possible_values(SchemaKind::VARIANTS))
+    )
+    (@subcommand import =>
+        (about: "write an experiment file and correct choices file that make existing configs again")
+        (@arg configs: <CONFIG>... "the configs to import; each one becomes a stimuli subset named after its file")
+        (@arg stimulus_format: --("stimulus-format") <FORMAT>
+            "the `format` the stimuli were named with, such as {motif}_{fg_db}")
+        (@arg decisive: -d --decisive <ATTRIBUTES> require_delimiter(true)
+            "the decisive attributes, separated by commas")
+    )
+    (@subcommand migrate =>
+        (about: "convert the correct choices file to the current format")
+    )
+    (@subcommand staircase =>
+        (about: "update the staircase from trial logs, and make the configs for its level")
+        (@arg logs: [TRIAL_LOG]... "decide trial logs, one JSON object per line, oldest first")
+        (@arg state: --state [STATE_YML] state_help)
+    )
+    (@subcommand cohort =>
+        (about: "make the configs for a cohort of subjects, and a schedule with the order of their sets")
+        (@arg subjects: <SUBJECT>... "the subjects in the cohort")
+        (@arg schedule: -o --schedule [SCHEDULE_CSV] schedule_help)
+    )
     )
     .get_matches();
 
     match matches.subcommand() {
         ("diff", Some(matches)) => config_diff(matches),
         ("stimuli", Some(_)) => list_stimuli(&matches),
+        ("validate", Some(_)) => validate_experiment(&matches),
+        ("render", Some(_)) => render_experiment(&matches),
+        ("schema", Some(schema)) => print_schema(schema),
+        ("import", Some(import)) => import_configs(&matches, import),
+        ("migrate", Some(_)) => migrate_choices(&matches),
+        ("cohort", Some(cohort)) => plan_cohort(&matches, cohort),
+        ("staircase", Some(staircase)) => run_staircase(&matches, staircase),
         _ => generate_configs(matches),
     }
 }
 
+fn experiment_name<'a>(matches: &'a ArgMatches) -> Result<&'a str> {
+    matches
+        .value_of("experiment")
+        .ok_or_else(|| anyhow!("must provide `experiment` file"))
+}
+
+/// Reads the experiment file in the format given with `--format` or by its extension,
+/// with any files it extends or includes merged in
+fn experiment_source(matches: &ArgMatches) -> Result<String> {
+    Ok(experiment_sources(matches)?.source)
+}
+
+/// Like `experiment_source`, along with the files that were merged
+fn experiment_sources(matches: &ArgMatches) -> Result<ExperimentSources> {
+    let format: Option<ExperimentFormat> =
+        matches.value_of("format").map(str::parse).transpose()?;
+    Ok(decide_config::experiment_sources(
+        experiment_name(matches)?,
+        format,
+    )?)
+}
+
+/// Reads the experiment file, returning its resolved source along with the experiment
+fn load_experiment(matches: &ArgMatches) -> Result<(String, Experiment)> {
+    let source = experiment_source(matches)?;
+    let experiment = serde_yaml::from_str(&source).context("could not parse experiment file")?;
+    Ok((source, experiment))
+}
+
+/// Prints the experiment with any files it extends or includes merged in
+fn render_experiment(matches: &ArgMatches) -> Result<()> {
+    let source = experiment_source(matches)?;
+    print!("{}", source);
+    Ok(())
+}
+
+fn print_schema(matches: &ArgMatches) -> Result<()> {
+    let kind: SchemaKind = matches.value_of("kind").unwrap().parse()?;
+    let stdout = io::stdout();
+    serde_json::to_writer_pretty(stdout.lock(), &decide_config::schema(kind))?;
+    println!();
+    Ok(())
+}
+
 fn generate_configs(matches: ArgMatches) -> Result<()> {
-    let experiment: Experiment =
-        serde_yaml::from_reader(
-            File::open(matches.value_of("experiment").ok_or_else(|| {
-                anyhow!("must provide `experiment` file if not using a subcommand")
-            })?)
-            .context("could not open experiment file")?,
-        )
-        .context("could not parse experiment file")?;
+    let (source, mut experiment) = load_experiment(&matches)?;
+    report_filters(&experiment);
+    report_parameters(&experiment);
+    let subject = match matches.value_of("subject") {
+        Some(id) => Some((id, load_subject(&matches, id, &experiment)?)),
+        None => None,
+    };
+    let correct_choices = match subject.as_ref() {
+        Some((_, subject)) => subject.correct_choices.clone(),
+        None => load_correct_choices(&matches, &experiment, &source)?,
+    };
+    lock_subsets(&matches, &mut experiment, &correct_choices)?;
+    let (configs, file_format) = match subject.as_ref() {
+        Some((id, subject)) => (
+            decide_config::make_subject_configs(&experiment, id, subject)?,
+            SUBJECT_FILE_FORMAT,
+        ),
+        None => (
+            decide_config::make_configs(&experiment, &correct_choices)?,
+            FILE_FORMAT,
+        ),
+    };
+    let format = config_format(&matches)?;
+    for (config, attributes) in configs {
+        let config = add_provenance(&matches, config, &source, &correct_choices, &attributes);
+        let name = config_file_name(&experiment, file_format, format, attributes)?;
+        write_config(&experiment, &config, name, format)?;
+    }
+    Ok(())
+}
+
+/// Moves the staircase through any new trials in the logs, and makes the configs for
+/// its current level
+fn run_staircase(matches: &ArgMatches, staircase: &ArgMatches) -> Result<()> {
+    let (source, mut experiment) = load_experiment(matches)?;
+    report_filters(&experiment);
+    report_parameters(&experiment);
+    let settings = experiment
+        .staircase()
+        .cloned()
+        .ok_or_else(|| anyhow!("the experiment file has no `staircase` settings"))?;
+    let state_name = staircase
+        .value_of("state")
+        .unwrap_or(DEFAULT_STAIRCASE_FILE);
+    let mut state: StaircaseState = match File::open(state_name) {
+        Ok(file) => serde_yaml::from_reader(file)
+            .with_context(|| format!("could not parse {}", state_name))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => settings.start(),
+        Err(e) => return Err(e).with_context(|| format!("could not open {}", state_name)),
+    };
+    let mut logs = Vec::new();
+    for log_name in staircase.values_of("logs").into_iter().flatten() {
+        let mut trials: Vec<LoggedTrial> = Vec::new();
+        let log = fs::read_to_string(log_name)
+            .with_context(|| format!("could not open trial log {}", log_name))?;
+        for (i, line) in log
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.trim().is_empty())
+        {
+            trials.push(
+                serde_json::from_str(line)
+                    .with_context(|| format!("could not parse line {} of {}", i + 1, log_name))?,
+            );
+        }
+        let path = fs::canonicalize(log_name)
+            .with_context(|| format!("could not find trial log {}", log_name))?;
+        logs.push((path.display().to_string(), trials));
+    }
+    settings.update(&mut state, &experiment, &logs)?;
+    let file = File::create(state_name).context("could not create staircase state file")?;
+    serde_yaml::to_writer(file, &state).context("could not write staircase state file")?;
+    eprintln!(
+        "staircase level {} after {} trials and {} reversals",
+        state.level, state.trials, state.reversals
+    );
+    let correct_choices = load_correct_choices(matches, &experiment, &source)?;
+    lock_subsets(matches, &mut experiment, &correct_choices)?;
+    let format = config_format(matches)?;
+    for (config, attributes) in settings.configs(&experiment, &correct_choices, &state)? {
+        let config = add_provenance(matches, config, &source, &correct_choices, &attributes);
+        let name = config_file_name(&experiment, FILE_FORMAT, format, attributes)?;
+        write_config(&experiment, &config, name, format)?;
+    }
+    Ok(())
+}
+
+/// Adds the provenance of the config if `--provenance` was given
+fn add_provenance(
+    matches: &ArgMatches,
+    config: DecideConfig,
+    source: &str,
+    correct_choices: &CorrectChoices,
+    attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+) -> DecideConfig {
+    if matches.is_present("provenance") {
+        config.with_provenance(Provenance::new(
+            source.as_bytes(),
+            correct_choices,
+            attributes,
+        ))
+    } else {
+        config
+    }
+}
+
+fn config_format(matches: &ArgMatches) -> Result<ConfigFormat> {
+    Ok(matches
+        .value_of("output_format")
+        .map(str::parse)
+        .transpose()?
+        .unwrap_or(ConfigFormat::Json))
+}
+
+/// Writes a config, and its trial sequence next to it if the experiment has one
+fn write_config(
+    experiment: &Experiment,
+    config: &DecideConfig,
+    name: String,
+    format: ConfigFormat,
+) -> Result<()> {
+    if let Some(settings) = experiment.trial_sequence() {
+        let sequence_name = Path::new(&name)
+            .with_extension("sequence.json")
+            .display()
+            .to_string();
+        let sequence = settings
+            .generate(config)
+            .with_context(|| format!("could not generate trial sequence for {}", name))?;
+        let file = File::create(&sequence_name)
+            .with_context(|| format!("could not create `{}`", sequence_name))?;
+        serde_json::to_writer_pretty(file, &sequence)
+            .with_context(|| format!("could not write `{}`", sequence_name))?;
+    }
+    config.write(&name, format)
+}
+
+/// The file name for a config, from `name_format` followed by `file_format` and the
+/// extension for `format`
+fn config_file_name<A: FormatArgs + fmt::Debug>(
+    experiment: &Experiment,
+    file_format: &str,
+    format: ConfigFormat,
+    attributes: A,
+) -> Result<String> {
+    let format_str = format!(
+        "{}{}.{}",
+        experiment.name_format(),
+        file_format,
+        format.extension()
+    );
+    trace!("format string: {}", format_str);
+    trace!("attributes: {:?}", attributes);
+    Ok(SimpleCurlyFormat
+        .format(&format_str, attributes)
+        .map_err(|_| Error::Format)
+        .context("could not build file name for config")?
+        .into_owned())
+}
+
+/// Loads the correct choices file, creating it if it does not exist yet
+fn load_correct_choices(
+    matches: &ArgMatches,
+    experiment: &Experiment,
+    source: &str,
+) -> Result<CorrectChoices> {
     let correct_choices_name = matches
         .value_of("correct")
         .unwrap_or(DEFAULT_CORRECT_CHOICES_FILE);
-    let correct_choices = match File::open(correct_choices_name) {
-        Ok(file) => {
-            serde_yaml::from_reader(file).context("could not parse correct choices file")?
+    let mut choices_file = match read_choices_file(correct_choices_name)? {
+        Some(choices_file) => choices_file,
+        None => {
+            let seed = rand::random();
+            let choices = CorrectChoices::random(experiment, seed)?;
+            let choices_file = ChoicesFile::Versioned(VersionedChoices::new(
+                choices,
+                source.as_bytes(),
+                experiment.choices(),
+                Some(seed),
+            ));
+            write_choices_file(correct_choices_name, &choices_file)?;
+            choices_file
+        }
+    };
+    let extend = matches.is_present("extend_choices");
+    if reconcile_choices(
+        choices_file.correct_choices_mut(),
+        experiment,
+        correct_choices_name,
+        extend,
+    )? {
+        choices_file.extended(source.as_bytes());
+        write_choices_file(correct_choices_name, &choices_file)?;
+    }
+    Ok(choices_file.correct_choices().clone())
+}
+
+/// Loads a subject from the subject registry, registering it if it is new
+fn load_subject(matches: &ArgMatches, id: &str, experiment: &Experiment) -> Result<Subject> {
+    let registry_name = matches
+        .value_of("registry")
+        .unwrap_or(DEFAULT_REGISTRY_FILE);
+    let mut registry = read_registry(registry_name)?;
+    let extend = matches.is_present("extend_choices");
+    let changed = update_subjects(&mut registry, registry_name, &[id], experiment, extend)?;
+    if changed {
+        write_registry(registry_name, &registry)?;
+    }
+    Ok(registry.get(id).unwrap().clone())
+}
+
+/// Registers the subjects in `ids` that are new, and reconciles the correct choices
+/// they share with the experiment. Returns whether the registry changed.
+fn update_subjects(
+    registry: &mut SubjectRegistry,
+    registry_name: &str,
+    ids: &[&str],
+    experiment: &Experiment,
+    extend: bool,
+) -> Result<bool> {
+    let mut changed = false;
+    for &id in ids {
+        if registry.get(id).is_none() {
+            let subject = registry.register(id, experiment)?;
+            eprintln!(
+                "registered subject {} ({}inverted) in {}",
+                id,
+                if subject.inverted { "" } else { "not " },
+                registry_name
+            );
+            changed = true;
+        }
+    }
+    if let Some(correct_choices) = registry.correct_choices_mut() {
+        if reconcile_choices(correct_choices, experiment, registry_name, extend)? {
+            registry.extended();
+            changed = true;
         }
+    }
+    Ok(changed)
+}
+
+/// Makes the configs for each subject in a cohort, with the sets in an order from a
+/// balanced Latin square, and writes the schedule of configs for each subject
+fn plan_cohort(matches: &ArgMatches, cohort: &ArgMatches) -> Result<()> {
+    let (source, mut experiment) = load_experiment(matches)?;
+    report_filters(&experiment);
+    report_parameters(&experiment);
+    let ids: Vec<&str> = cohort.values_of("subjects").unwrap().unique().collect();
+    let registry_name = matches
+        .value_of("registry")
+        .unwrap_or(DEFAULT_REGISTRY_FILE);
+    let mut registry = read_registry(registry_name)?;
+    let extend = matches.is_present("extend_choices");
+    update_subjects(&mut registry, registry_name, &ids, &experiment, extend)?;
+    // generated subsets are balanced with the first subject's correct choices
+    let first_choices = registry.get(ids[0]).unwrap().correct_choices.clone();
+    lock_subsets(matches, &mut experiment, &first_choices)?;
+    let n_sets = experiment.stimuli_subsets()?.len();
+    registry.assign_sequences(&ids, balanced_latin_square(n_sets).len());
+    write_registry(registry_name, &registry)?;
+    let format = config_format(matches)?;
+    let schedule_name = cohort.value_of("schedule").unwrap_or(DEFAULT_SCHEDULE_FILE);
+    let mut schedule = csv::Writer::from_path(schedule_name)
+        .with_context(|| format!("could not create {}", schedule_name))?;
+    for &id in ids.iter() {
+        let subject = registry.get(id).unwrap();
+        for (session, (config, attributes)) in cohort_configs(&experiment, id, subject)? {
+            let config = add_provenance(
+                matches,
+                config,
+                &source,
+                &subject.correct_choices,
+                &attributes,
+            );
+            let row = ScheduleRow {
+                subject: String::from(id),
+                inverted: attributes["inverted"].to_string(),
+                session,
+                set: attributes["set"].to_string(),
+                config: config_file_name(&experiment, SUBJECT_FILE_FORMAT, format, attributes)?,
+            };
+            write_config(&experiment, &config, row.config.clone(), format)?;
+            schedule
+                .serialize(row)
+                .context("could not write schedule")?;
+        }
+    }
+    schedule.flush().context("could not write schedule")?;
+    eprintln!(
+        "wrote schedule for {} subjects to {}",
+        ids.len(),
+        schedule_name
+    );
+    Ok(())
+}
+
+/// Warns about correct choices for values that are no longer in the experiment, and
+/// assigns correct choices to new values if `extend` is set. Returns whether the
+/// correct choices changed.
+fn reconcile_choices(
+    correct_choices: &mut CorrectChoices,
+    experiment: &Experiment,
+    name: &str,
+    extend: bool,
+) -> Result<bool> {
+    correct_choices
+        .split_values(experiment)
+        .with_context(|| format!("invalid correct choices in {}", name))?;
+    let diff = correct_choices.reconcile(experiment);
+    if !diff.removed.is_empty() {
+        eprintln!(
+            "warning: {} lists decisive values that are no longer in the experiment: {}",
+            name,
+            diff.removed.iter().join(", ")
+        );
+    }
+    if diff.added.is_empty() {
+        return Ok(false);
+    }
+    if !extend {
+        return Err(anyhow!(
+            "{} has no correct choice for {}; use --extend-choices to assign them",
+            name,
+            diff.added.iter().join(", ")
+        ));
+    }
+    correct_choices.extend(experiment, &diff.added);
+    eprintln!(
+        "assigned correct choices to {} in {}",
+        diff.added.iter().join(", "),
+        name
+    );
+    Ok(true)
+}
+
+/// Replaces generated `stimuli_subsets` with the ones in the subsets file, generating
+/// them first if the file does not exist yet
+fn lock_subsets(
+    matches: &ArgMatches,
+    experiment: &mut Experiment,
+    correct_choices: &CorrectChoices,
+) -> Result<()> {
+    let split = match experiment.subset_generator() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let subsets_name = matches.value_of("subsets").unwrap_or(DEFAULT_SUBSETS_FILE);
+    let lock: SubsetLock = match File::open(subsets_name) {
+        Ok(file) => serde_yaml::from_reader(file).context("could not parse subsets file")?,
         Err(e) => {
             if let io::ErrorKind::NotFound = e.kind() {
-                let choices = CorrectChoices::random(&experiment)?;
-                let file = File::create(correct_choices_name)
-                    .context("could not create correct choices file")?;
-                serde_yaml::to_writer(file, &choices)
-                    .context("could not write correct choices file")?;
-                Ok(choices)
+                let lock = split.generate(experiment, correct_choices)?;
+                let file = File::create(subsets_name).context("could not create subsets file")?;
+                serde_yaml::to_writer(file, &lock).context("could not write subsets file")?;
+                Ok(lock)
             } else {
                 Err(e)
             }
         }?,
     };
-    for (config, attributes) in decide_config::make_configs(&experiment, &correct_choices)? {
-        let format_str =
-            String::from(experiment.name_format()) + "-set{set}-inverted{inverted}.json";
-        trace!("format string: {}", format_str);
-        trace!("attributes: {:?}", attributes);
-        let formatted_name = SimpleCurlyFormat
-            .format(&format_str, attributes)
-            .map_err(|_| Error::Format)
-            .context("could not build file name for config")?
-            .into_owned();
-        config.to_json(formatted_name)?;
+    if &lock.split != split {
+        return Err(anyhow!(
+            "{} was generated with different `stimuli_subsets` settings; \
+             delete it to generate new subsets",
+            subsets_name
+        ));
+    }
+    experiment
+        .set_subsets(lock.subsets)
+        .with_context(|| format!("invalid subsets in {}", subsets_name))
+}
+
+/// Reads the subject registry, or an empty one if it does not exist
+fn read_registry(name: &str) -> Result<SubjectRegistry> {
+    match File::open(name) {
+        Ok(file) => serde_yaml::from_reader(file)
+            .with_context(|| format!("could not parse subject registry {}", name)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SubjectRegistry::default()),
+        Err(e) => Err(e).with_context(|| format!("could not open {}", name)),
+    }
+}
+
+fn write_registry(name: &str, registry: &SubjectRegistry) -> Result<()> {
+    let file = File::create(name).context("could not create subject registry")?;
+    serde_yaml::to_writer(file, registry).context("could not write subject registry")
+}
+
+/// Reads a correct choices file in either format, or `None` if it does not exist
+fn read_choices_file(name: &str) -> Result<Option<ChoicesFile>> {
+    let file = match File::open(name) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("could not open {}", name)),
+    };
+    let choices_file: ChoicesFile = serde_yaml::from_reader(file)
+        .with_context(|| format!("could not parse correct choices file {}", name))?;
+    Ok(Some(choices_file))
+}
+
+fn write_choices_file(name: &str, choices_file: &ChoicesFile) -> Result<()> {
+    let file = File::create(name).context("could not create correct choices file")?;
+    serde_yaml::to_writer(file, choices_file).context("could not write correct choices file")
+}
+
+fn migrate_choices(matches: &ArgMatches) -> Result<()> {
+    let (source, experiment) = load_experiment(matches)?;
+    let correct_choices_name = matches
+        .value_of("correct")
+        .unwrap_or(DEFAULT_CORRECT_CHOICES_FILE);
+    let choices_file = read_choices_file(correct_choices_name)?
+        .ok_or_else(|| anyhow!("{} does not exist", correct_choices_name))?;
+    if !choices_file.is_legacy() {
+        eprintln!("{} is already in the current format", correct_choices_name);
+        return Ok(());
     }
+    let choices_file = choices_file.migrate(source.as_bytes(), experiment.choices());
+    write_choices_file(correct_choices_name, &choices_file)?;
+    eprintln!("migrated {}", correct_choices_name);
     Ok(())
 }
 
+/// Writes the experiment file and correct choices file that existing configs were made
+/// from, and checks that they make the same configs again
+fn import_configs(matches: &ArgMatches, import: &ArgMatches) -> Result<()> {
+    let experiment_name = experiment_name(matches)?;
+    let correct_choices_name = matches
+        .value_of("correct")
+        .unwrap_or(DEFAULT_CORRECT_CHOICES_FILE);
+    for name in [experiment_name, correct_choices_name].iter() {
+        if Path::new(name).exists() {
+            return Err(anyhow!("{} already exists", name));
+        }
+    }
+    let config_names: Vec<&str> = import.values_of("configs").unwrap().collect();
+    let configs = config_names
+        .iter()
+        .map(|name| {
+            let set = file_stem(name);
+            Ok((set, read_config(name)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let decisive: Vec<AttributeLabel> = import
+        .values_of("decisive")
+        .unwrap()
+        .map(AttributeLabel::from)
+        .collect();
+    let imported = decide_config::import_configs(
+        &configs,
+        import.value_of("stimulus_format").unwrap(),
+        &decisive,
+        &file_stem(experiment_name),
+    )?;
+    fs::write(experiment_name, &imported.source)
+        .with_context(|| format!("could not write {}", experiment_name))?;
+    let choices_file = ChoicesFile::Versioned(VersionedChoices::new(
+        imported.correct_choices.clone(),
+        imported.source.as_bytes(),
+        imported.experiment.choices(),
+        None,
+    ));
+    write_choices_file(correct_choices_name, &choices_file)?;
+    let mismatches = imported.mismatches(&configs)?;
+    if mismatches.is_empty() {
+        eprintln!(
+            "wrote {} and {}, which make the same {} configs again",
+            experiment_name,
+            correct_choices_name,
+            configs.len()
+        );
+        return Ok(());
+    }
+    let stdout = io::stdout();
+    for (set, regenerated) in mismatches.iter() {
+        let (name, (_, original)) = config_names
+            .iter()
+            .zip(configs.iter())
+            .find(|(_, (s, _))| s == set)
+            .unwrap();
+        eprintln!("{} is not made again the same way:", name);
+        let original = original.clone().with_decide_defaults();
+        serde_json::to_writer_pretty(stdout.lock(), &Diff::serializable(&original, regenerated))?;
+        println!();
+    }
+    eprintln!(
+        "wrote {} and {}, but {} of the {} configs are not made again the same way",
+        experiment_name,
+        correct_choices_name,
+        mismatches.len(),
+        configs.len()
+    );
+    std::process::exit(1)
+}
+
+fn file_stem(name: &str) -> String {
+    Path::new(name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from(name))
+}
+
 fn config_diff(matches: &ArgMatches) -> Result<()> {
     let file1_name = matches.value_of("file1").unwrap();
     let file2_name = matches.value_of("file2").unwrap();
-    let file1: DecideConfig = serde_json::from_reader(File::open(file1_name)?)
-        .with_context(|| format!("could not parse {}", file1_name))?;
-    let file2: DecideConfig = serde_json::from_reader(File::open(file2_name)?)
-        .with_context(|| format!("could not parse {}", file2_name))?;
+    let file1 = read_config(file1_name)?.with_decide_defaults();
+    let file2 = read_config(file2_name)?.with_decide_defaults();
     if file1 == file2 {
         std::process::exit(0)
     } else {
@@ -102,18 +686,61 @@ fn config_diff(matches: &ArgMatches) -> Result<()> {
     }
 }
 
+/// Reads a config written in any of the output formats
+fn read_config(name: &str) -> Result<DecideConfig> {
+    let file = File::open(name).with_context(|| format!("could not open {}", name))?;
+    let config = if name.ends_with(".yml") || name.ends_with(".yaml") {
+        serde_yaml::from_reader(file).with_context(|| format!("could not parse {}", name))?
+    } else {
+        serde_json::from_reader(file).with_context(|| format!("could not parse {}", name))?
+    };
+    Ok(config)
+}
+
 fn list_stimuli(matches: &ArgMatches) -> Result<()> {
-    let experiment: Experiment = serde_yaml::from_reader(
-        File::open(
-            matches
-                .value_of("experiment")
-                .ok_or_else(|| anyhow!("must provide `experiment` file"))?,
-        )
-        .context("could not open experiment file")?,
-    )
-    .context("could not parse experiment file")?;
+    let (_, experiment) = load_experiment(matches)?;
+    report_filters(&experiment);
     for stimulus in experiment.stimuli() {
         println!("{}", String::from(stimulus));
     }
     Ok(())
 }
+
+fn report_filters(experiment: &Experiment) {
+    for (rule, removed) in experiment.filter_report() {
+        eprintln!("{} removed {} stimuli", rule, removed);
+    }
+}
+
+fn report_parameters(experiment: &Experiment) {
+    for key in experiment.unknown_parameters() {
+        if experiment.drops_unknown_parameters() {
+            eprintln!(
+                "warning: the parameter {} is not known, and was left out of the configs; \
+                 list it in `extra_parameters` to keep it",
+                key
+            );
+        } else {
+            eprintln!(
+                "warning: the parameter {} is not known, but was passed on to decide; \
+                 list it in `extra_parameters` if it is meant to be",
+                key
+            );
+        }
+    }
+}
+
+fn validate_experiment(matches: &ArgMatches) -> Result<()> {
+    let experiment_name = experiment_name(matches)?;
+    let sources = experiment_sources(matches)?;
+    let diagnostics = decide_config::validate_sources(&sources);
+    if diagnostics.is_empty() {
+        eprintln!("{}: no problems found", experiment_name);
+        Ok(())
+    } else {
+        for diagnostic in diagnostics.iter() {
+            println!("{}", diagnostic);
+        }
+        std::process::exit(1)
+    }
+}
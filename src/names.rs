@@ -0,0 +1,153 @@
+use super::{format_args, AttributeLabel, Error, StimulusAttribute};
+use itertools::Itertools;
+use std::collections::HashMap;
+
+/// A stimuli `format`, used to read the attributes of a stimulus back out of its name
+#[derive(Clone, Debug)]
+pub struct NameFormat {
+    format: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Text(String),
+    Placeholder(AttributeLabel),
+}
+
+type Attributes = HashMap<AttributeLabel, StimulusAttribute>;
+
+impl NameFormat {
+    pub fn new(format: &str) -> Result<Self, Error> {
+        let mut segments = Vec::new();
+        let mut rest = format;
+        for label in format_args(format)? {
+            let placeholder = format!("{{{}}}", label);
+            let start = rest.find(&placeholder).ok_or(Error::Format)?;
+            if start > 0 {
+                segments.push(Segment::Text(String::from(&rest[..start])));
+            }
+            segments.push(Segment::Placeholder(AttributeLabel::from(label)));
+            rest = &rest[start + placeholder.len()..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(String::from(rest)));
+        }
+        Ok(NameFormat {
+            format: String::from(format),
+            segments,
+        })
+    }
+
+    /// The attributes in the format, in the order they first appear
+    pub fn labels(&self) -> Vec<&AttributeLabel> {
+        self.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Placeholder(label) => Some(label),
+                Segment::Text(_) => None,
+            })
+            .unique()
+            .collect()
+    }
+
+    /// The attributes of the stimulus named `name`. Each placeholder stands for at
+    /// least one character, and a placeholder used twice must have the same value
+    /// both times. It is an error if the name can be read in more than one way, as
+    /// `a_b_30` can with `{motif}_{fg_db}`.
+    pub fn parse(&self, name: &str) -> Result<Attributes, Error> {
+        let mut readings = Vec::new();
+        self.read(name, 0, &mut HashMap::new(), &mut readings);
+        let mut readings = readings.into_iter();
+        match (readings.next(), readings.next()) {
+            (Some(attributes), None) => Ok(attributes
+                .into_iter()
+                .map(|(label, value)| (label.clone(), value.parse().unwrap()))
+                .collect()),
+            (None, _) => Err(Error::StimulusNameMismatch(
+                String::from(name),
+                self.format.clone(),
+            )),
+            (Some(first), Some(second)) => Err(Error::AmbiguousStimulusName {
+                name: String::from(name),
+                format: self.format.clone(),
+                first: describe(&first),
+                second: describe(&second),
+            }),
+        }
+    }
+
+    /// Adds the ways the rest of the name can be read, starting at `segment`, to
+    /// `readings`, stopping once there are two
+    fn read<'a>(
+        &'a self,
+        rest: &str,
+        segment: usize,
+        attributes: &mut HashMap<&'a AttributeLabel, String>,
+        readings: &mut Vec<HashMap<&'a AttributeLabel, String>>,
+    ) {
+        if readings.len() > 1 {
+            return;
+        }
+        match self.segments.get(segment) {
+            None if rest.is_empty() => readings.push(attributes.clone()),
+            None => {}
+            Some(Segment::Text(text)) => {
+                if let Some(rest) = rest.strip_prefix(&text[..]) {
+                    self.read(rest, segment + 1, attributes, readings);
+                }
+            }
+            Some(Segment::Placeholder(label)) => {
+                if let Some(value) = attributes.get(label) {
+                    if let Some(rest) = rest.strip_prefix(&value[..]) {
+                        self.read(rest, segment + 1, attributes, readings);
+                    }
+                    return;
+                }
+                for (end, _) in rest.char_indices().skip(1).chain(Some((rest.len(), ' '))) {
+                    attributes.insert(label, String::from(&rest[..end]));
+                    self.read(&rest[end..], segment + 1, attributes, readings);
+                    attributes.remove(label);
+                }
+            }
+        }
+    }
+}
+
+/// A reading of a name, such as `{fg_db: 30, motif: a_b}`
+fn describe(attributes: &HashMap<&AttributeLabel, String>) -> String {
+    let attributes = attributes
+        .iter()
+        .sorted_by_key(|(label, _)| **label)
+        .map(|(label, value)| format!("{}: {}", label, value));
+    format!("{{{}}}", attributes.format(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_names() {
+        let format = NameFormat::new("{motif}_{fg_db}").unwrap();
+        let attributes = format.parse("g29wxi4q_30").unwrap();
+        assert_eq!(attributes["motif"], StimulusAttribute::from("g29wxi4q"));
+        assert_eq!(attributes["fg_db"], StimulusAttribute::from(30));
+        assert!(matches!(
+            format.parse("g29wxi4q30"),
+            Err(Error::StimulusNameMismatch(..))
+        ));
+        match format.parse("a_b_30") {
+            Err(Error::AmbiguousStimulusName { first, second, .. }) => {
+                assert_eq!(first, "{fg_db: b_30, motif: a}");
+                assert_eq!(second, "{fg_db: 30, motif: a_b}");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let attributes = format.parse("0042_30").unwrap();
+        assert_eq!(attributes["motif"], StimulusAttribute::from("0042"));
+        let repeated = NameFormat::new("{a}{b}-{a}").unwrap();
+        let attributes = repeated.parse("xyy-x").unwrap();
+        assert_eq!(attributes["b"], StimulusAttribute::from("yy"));
+    }
+}
@@ -0,0 +1,391 @@
+use super::{format_args, validate::Problem, AttributeLabel, Error, Response, StimulusAttribute};
+use dynfmt::{curly::SimpleCurlyFormat, Format};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_value::Value;
+use serde_with::skip_serializing_none;
+use std::collections::{BTreeMap, HashMap};
+
+/// The `parameters` passed on to decide. The ones decide is known to use are
+/// checked; any others are passed on as they are.
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, JsonSchema, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Parameters {
+    correct_timeout: Option<Param<bool>>,
+    rand_replace: Option<Param<bool>>,
+    init_position: Option<Param<Response>>,
+    /// milliseconds
+    response_window: Option<Param<u32>>,
+    /// milliseconds
+    feed_duration: Option<Param<u32>>,
+    /// milliseconds
+    lightsout_duration: Option<Param<u32>>,
+    max_corrections: Option<Param<u32>>,
+    #[serde(flatten)]
+    #[schemars(with = "BTreeMap<String, serde_json::Value>")]
+    extra: BTreeMap<String, Value>,
+}
+
+/// A parameter value, or a template such as `"{fg_db_window}"` that is filled in with
+/// the attributes of each config
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+#[serde(untagged)]
+pub enum Param<T> {
+    Value(T),
+    Template(String),
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Param<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(template) if template.contains('{') => Ok(Param::Template(template)),
+            value => T::deserialize(whole_number(value))
+                .map(Param::Value)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Clone> Param<T> {
+    /// Fills in a template, reading the result like a value in the experiment file,
+    /// so that `"2000"` becomes a number and `"true"` a boolean
+    fn render(
+        &self,
+        name: &str,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+    ) -> Result<Self, Error> {
+        match self {
+            Param::Value(_) => Ok(self.clone()),
+            Param::Template(template) => {
+                let rendered = render_template(name, template, attributes)?;
+                serde_yaml::from_str(&rendered)
+                    .ok()
+                    .and_then(|value| T::deserialize(whole_number(value)).ok())
+                    .map(Param::Value)
+                    .ok_or_else(|| {
+                        Error::InvalidParameter(
+                            String::from(name),
+                            format!("was `{}`, which is not a valid value", rendered),
+                        )
+                    })
+            }
+        }
+    }
+}
+
+/// Reads a whole number written with a decimal point, such as `2000.0`, as an integer
+fn whole_number(value: Value) -> Value {
+    match value {
+        Value::F64(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+            if number < 0.0 {
+                Value::I64(number as i64)
+            } else {
+                Value::U64(number as u64)
+            }
+        }
+        Value::F32(number) => whole_number(Value::F64(number.into())),
+        value => value,
+    }
+}
+
+/// `parameters` with decide's defaults filled in for the known parameters that are
+/// not set, with numbers as they are read back from a config file. Empty parameters
+/// are read as a mapping with nothing set.
+pub fn with_decide_defaults(parameters: &Value) -> Value {
+    let empty = Value::Map(BTreeMap::new());
+    let parameters = match parameters {
+        Value::Unit => &empty,
+        parameters => parameters,
+    };
+    match parameters {
+        Value::Map(parameters) => {
+            let defaults = serde_json::to_value(Parameters::decide_defaults())
+                .and_then(serde_json::from_value)
+                .expect("the defaults can always be serialized");
+            let mut filled = match defaults {
+                Value::Map(defaults) => defaults,
+                _ => unreachable!("parameters are serialized as a map"),
+            };
+            filled.extend(parameters.clone());
+            Value::Map(filled)
+        }
+        parameters => parameters.clone(),
+    }
+}
+
+fn render_template(
+    name: &str,
+    template: &str,
+    attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+) -> Result<String, Error> {
+    SimpleCurlyFormat
+        .format(template, attributes)
+        .map(|rendered| rendered.into_owned())
+        .map_err(|_| {
+            Error::InvalidParameter(
+                String::from(name),
+                format!("could not fill in `{}`", template),
+            )
+        })
+}
+
+impl Parameters {
+    /// The values decide/gng.js uses for the parameters it knows, when they are not set.
+    /// They are only used to compare configs, and are not written to them.
+    fn decide_defaults() -> Self {
+        Parameters {
+            correct_timeout: Some(Param::Value(false)),
+            rand_replace: Some(Param::Value(true)),
+            init_position: Some(Param::Value(Response::PeckCenter)),
+            response_window: Some(Param::Value(2000)),
+            feed_duration: Some(Param::Value(4000)),
+            lightsout_duration: Some(Param::Value(10000)),
+            max_corrections: Some(Param::Value(10)),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Keys that decide is not known to use, and that are not listed in `pass_through`
+    pub fn unknown_keys<'a>(&'a self, pass_through: &'a [String]) -> impl Iterator<Item = &'a str> {
+        self.extra
+            .keys()
+            .filter(move |key| !pass_through.contains(key))
+            .map(String::as_str)
+    }
+
+    /// Checks values that are not templates, and that templates only use `placeholders`.
+    /// `path` is where the parameters are in the experiment file.
+    pub fn problems(&self, path: &str, placeholders: &[&str]) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let durations = [
+            ("response_window", &self.response_window),
+            ("feed_duration", &self.feed_duration),
+        ];
+        for (name, duration) in durations.iter() {
+            if let Some(Param::Value(0)) = duration {
+                problems.push(Problem::new(
+                    format!("{}.{}", path, name),
+                    Error::InvalidParameter(
+                        String::from(*name),
+                        String::from("must be more than 0"),
+                    ),
+                ));
+            }
+        }
+        for (name, template) in self.templates() {
+            let error = match format_args(template) {
+                Ok(args) => match args.into_iter().find(|arg| !placeholders.contains(arg)) {
+                    Some(arg) => Error::InvalidParameter(
+                        String::from(name),
+                        format!(
+                            "uses {}, which is not held constant in `name_format`, `set` or `inverted`",
+                            arg
+                        ),
+                    ),
+                    None => continue,
+                },
+                Err(e) => e,
+            };
+            problems.push(Problem::new(format!("{}.{}", path, name), error));
+        }
+        problems
+    }
+
+    fn templates(&self) -> Vec<(&str, &str)> {
+        fn template<'a, T>(
+            name: &'a str,
+            param: &'a Option<Param<T>>,
+        ) -> Option<(&'a str, &'a str)> {
+            match param {
+                Some(Param::Template(template)) => Some((name, template)),
+                _ => None,
+            }
+        }
+        let mut templates: Vec<_> = vec![
+            template("correct_timeout", &self.correct_timeout),
+            template("rand_replace", &self.rand_replace),
+            template("init_position", &self.init_position),
+            template("response_window", &self.response_window),
+            template("feed_duration", &self.feed_duration),
+            template("lightsout_duration", &self.lightsout_duration),
+            template("max_corrections", &self.max_corrections),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        templates.extend(self.extra.iter().filter_map(|(name, value)| match value {
+            Value::String(template) if template.contains('{') => Some((&name[..], &template[..])),
+            _ => None,
+        }));
+        templates
+    }
+
+    /// These parameters, with any set in `overrides` replaced
+    pub fn merge(&self, overrides: &Parameters) -> Parameters {
+        let mut extra = self.extra.clone();
+        extra.extend(overrides.extra.clone());
+        Parameters {
+            correct_timeout: overrides
+                .correct_timeout
+                .clone()
+                .or_else(|| self.correct_timeout.clone()),
+            rand_replace: overrides
+                .rand_replace
+                .clone()
+                .or_else(|| self.rand_replace.clone()),
+            init_position: overrides
+                .init_position
+                .clone()
+                .or_else(|| self.init_position.clone()),
+            response_window: overrides
+                .response_window
+                .clone()
+                .or_else(|| self.response_window.clone()),
+            feed_duration: overrides
+                .feed_duration
+                .clone()
+                .or_else(|| self.feed_duration.clone()),
+            lightsout_duration: overrides
+                .lightsout_duration
+                .clone()
+                .or_else(|| self.lightsout_duration.clone()),
+            max_corrections: overrides
+                .max_corrections
+                .clone()
+                .or_else(|| self.max_corrections.clone()),
+            extra,
+        }
+    }
+
+    /// The parameters for a config with `attributes`, with the templates filled in.
+    /// If `pass_through` is given, unknown keys that are not in it are left out.
+    pub fn render(
+        &self,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+        pass_through: Option<&[String]>,
+    ) -> Result<Value, Error> {
+        fn render<T: DeserializeOwned + Clone>(
+            name: &str,
+            param: &Option<Param<T>>,
+            attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+        ) -> Result<Option<Param<T>>, Error> {
+            param
+                .as_ref()
+                .map(|p| p.render(name, attributes))
+                .transpose()
+        }
+        let extra = self
+            .extra
+            .iter()
+            .filter(|(key, _)| pass_through.is_none_or(|keys| keys.contains(key)))
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(template) if template.contains('{') => {
+                        let rendered = render_template(key, template, attributes)?;
+                        serde_yaml::from_str(&rendered).unwrap_or(Value::String(rendered))
+                    }
+                    value => value.clone(),
+                };
+                Ok((key.clone(), value))
+            })
+            .collect::<Result<_, Error>>()?;
+        let rendered = Parameters {
+            correct_timeout: render("correct_timeout", &self.correct_timeout, attributes)?,
+            rand_replace: render("rand_replace", &self.rand_replace, attributes)?,
+            init_position: render("init_position", &self.init_position, attributes)?,
+            response_window: render("response_window", &self.response_window, attributes)?,
+            feed_duration: render("feed_duration", &self.feed_duration, attributes)?,
+            lightsout_duration: render("lightsout_duration", &self.lightsout_duration, attributes)?,
+            max_corrections: render("max_corrections", &self.max_corrections, attributes)?,
+            extra,
+        };
+        Ok(serde_value::to_value(rendered).expect("parameters can always be serialized"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_parameters() {
+        let parameters: Parameters = serde_yaml::from_str(
+            "
+            correct_timeout: false
+            init_position: peck_center
+            response_window: 0
+            init_postion: peck_left
+            stim_gap: 2
+            ",
+        )
+        .unwrap();
+        let pass_through = vec![String::from("stim_gap")];
+        let unknown: Vec<_> = parameters.unknown_keys(&pass_through).collect();
+        assert_eq!(unknown, vec!["init_postion"]);
+        assert_eq!(parameters.problems("parameters", &[]).len(), 1);
+        let value = parameters.render(&HashMap::new(), None).unwrap();
+        assert_eq!(
+            serde_json::to_value(value).unwrap(),
+            serde_json::json!({
+                "correct_timeout": false,
+                "init_position": "peck_center",
+                "response_window": 0,
+                "init_postion": "peck_left",
+                "stim_gap": 2,
+            })
+        );
+        let value = parameters
+            .render(&HashMap::new(), Some(&pass_through))
+            .unwrap();
+        let keys: Vec<_> = serde_json::to_value(value)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert!(!keys.contains(&String::from("init_postion")));
+        assert!(keys.contains(&String::from("stim_gap")));
+        let defaults = with_decide_defaults(&serde_value::to_value(&parameters).unwrap());
+        assert_eq!(
+            serde_json::to_value(defaults).unwrap()["feed_duration"],
+            serde_json::json!(4000)
+        );
+        assert!(serde_yaml::from_str::<Parameters>("feed_duration: soon").is_err());
+        assert!(serde_yaml::from_str::<Parameters>("feed_duration: 2000.0").is_ok());
+        assert!(serde_yaml::from_str::<Parameters>("feed_duration: 2000.5").is_err());
+    }
+
+    #[test]
+    fn parameter_templates() {
+        let parameters: Parameters = serde_yaml::from_str(
+            "
+            response_window: '{window}'
+            correct_timeout: '{timeout}'
+            cue: 'light_{set}'
+            ",
+        )
+        .unwrap();
+        let problems = parameters.problems("parameters", &["window", "set"]);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "parameters.correct_timeout");
+        let overrides: Parameters = serde_yaml::from_str("correct_timeout: true").unwrap();
+        let attributes: HashMap<_, _> = vec![("window", "2500.0"), ("set", "train")]
+            .into_iter()
+            .map(|(k, v)| (AttributeLabel::from(k), StimulusAttribute::from(v)))
+            .collect();
+        let value = parameters
+            .merge(&overrides)
+            .render(&attributes, None)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(value).unwrap(),
+            serde_json::json!({
+                "correct_timeout": true,
+                "response_window": 2500,
+                "cue": "light_train",
+            })
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use super::{experiment_hash, AttributeLabel, CorrectChoices, StimulusAttribute};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Where a config came from. It is written along with the config, but decide doesn't
+/// use it, so it is ignored when configs are compared.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Debug)]
+pub struct Provenance {
+    /// the SHA-256 hash of the experiment file
+    pub experiment: String,
+    /// the SHA-256 hash of the correct choices, before they are inverted
+    pub correct_choices: String,
+    pub tool_version: String,
+    pub set: String,
+    pub inverted: bool,
+    /// the held-constant attributes of the config, and its subject if it has one
+    pub attributes: BTreeMap<AttributeLabel, StimulusAttribute>,
+}
+
+impl Provenance {
+    /// The provenance of a config made from `experiment_source` with `correct_choices`,
+    /// where `attributes` are the ones its name is made from
+    pub fn new(
+        experiment_source: &[u8],
+        correct_choices: &CorrectChoices,
+        attributes: &HashMap<AttributeLabel, StimulusAttribute>,
+    ) -> Self {
+        let mut attributes: BTreeMap<_, _> = attributes
+            .iter()
+            .map(|(label, value)| (label.clone(), value.clone()))
+            .collect();
+        let set = attributes
+            .remove("set")
+            .map(|set| set.to_string())
+            .unwrap_or_default();
+        let inverted = attributes.remove("inverted") == Some(StimulusAttribute::from("Yes"));
+        Provenance {
+            experiment: experiment_hash(experiment_source),
+            correct_choices: correct_choices.hash(),
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            set,
+            inverted,
+            attributes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DecideConfig;
+
+    #[test]
+    fn provenance_is_not_compared() {
+        let attributes: HashMap<_, _> = vec![("set", "train"), ("inverted", "Yes"), ("bg", "n1")]
+            .into_iter()
+            .map(|(k, v)| (AttributeLabel::from(k), StimulusAttribute::from(v)))
+            .collect();
+        let provenance = Provenance::new(
+            b"",
+            &serde_yaml::from_str("a: peck_left").unwrap(),
+            &attributes,
+        );
+        assert_eq!((&provenance.set[..], provenance.inverted), ("train", true));
+        assert_eq!(provenance.attributes.len(), 1);
+        let config: DecideConfig =
+            serde_json::from_str(r#"{"parameters": {}, "stimulus_root": "/", "stimuli": []}"#)
+                .unwrap();
+        let traced = config.clone().with_provenance(provenance);
+        let json = serde_json::to_value(&traced).unwrap();
+        assert_eq!(json["provenance"]["attributes"]["bg"], "n1");
+        assert!(serde_json::from_value::<DecideConfig>(json).unwrap() == config);
+    }
+}
@@ -0,0 +1,50 @@
+use super::{stimulus::StimuliConfig, ChoicesFile, DecideConfig, Experiment};
+use schemars::{schema::RootSchema, schema_for};
+use strum::{EnumString, EnumVariantNames};
+
+/// The files that a JSON Schema can be made for
+#[derive(EnumString, EnumVariantNames, Clone, Copy, Debug)]
+#[strum(serialize_all = "kebab-case")]
+pub enum SchemaKind {
+    /// an experiment file
+    Experiment,
+    /// the `stimuli` section of an experiment file
+    Stimuli,
+    /// a config written for decide
+    Config,
+    /// a correct choices file, in the current format or as written by older versions
+    CorrectChoices,
+}
+
+/// A JSON Schema for the files of `kind`, for editors and other tools to check them with
+pub fn schema(kind: SchemaKind) -> RootSchema {
+    match kind {
+        SchemaKind::Experiment => schema_for!(Experiment),
+        SchemaKind::Stimuli => schema_for!(StimuliConfig),
+        SchemaKind::Config => schema_for!(DecideConfig),
+        SchemaKind::CorrectChoices => schema_for!(ChoicesFile),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stimuli_schema() {
+        let experiment = serde_json::to_value(schema(SchemaKind::Experiment)).unwrap();
+        assert_eq!(
+            experiment["required"],
+            serde_json::json!(["decide", "stimuli"])
+        );
+        let stimuli = &experiment["definitions"]["StimuliConfig"];
+        assert_eq!(
+            stimuli["additionalProperties"]["$ref"],
+            "#/definitions/AttributeConfig"
+        );
+        assert_eq!(
+            stimuli["required"],
+            serde_json::json!(["decisive_attribute", "format"])
+        );
+    }
+}
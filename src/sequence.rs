@@ -0,0 +1,231 @@
+use super::{DecideConfig, Error, Response};
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How many times a block is reshuffled before giving up on `max_run`
+const MAX_ATTEMPTS: usize = 1000;
+
+/// Settings for generating a fixed sequence of trials for each config, instead of
+/// letting decide sample the stimuli by `frequency`
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceConfig {
+    order: SequenceOrder,
+    blocks: usize,
+    /// the number of trials in each block of a `gellermann` sequence
+    #[serde(default = "default_block_size")]
+    block_size: usize,
+    /// the most trials in a row that can have the same correct response
+    max_run: usize,
+    #[serde(default)]
+    seed: u64,
+}
+
+fn default_block_size() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceOrder {
+    /// each block has every stimulus `frequency` times
+    Block,
+    /// each block has every correct response equally often, with stimuli drawn at random
+    Gellermann,
+}
+
+/// A sequence of trials, written alongside the config it was generated from
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrialSequence {
+    pub settings: SequenceConfig,
+    pub trials: Vec<Trial>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Trial {
+    pub stimulus: String,
+    pub correct: Option<Response>,
+}
+
+impl SequenceConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.blocks == 0 {
+            Err(Error::InvalidSequence(String::from(
+                "`blocks` must be at least 1",
+            )))
+        } else if self.max_run == 0 {
+            Err(Error::InvalidSequence(String::from(
+                "`max_run` must be at least 1",
+            )))
+        } else if self.order == SequenceOrder::Gellermann && self.block_size == 0 {
+            Err(Error::InvalidSequence(String::from(
+                "`block_size` must be at least 1",
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Generates a sequence of trials from the stimuli in `config`. The same seed and
+    /// stimuli always give the same sequence.
+    pub fn generate(&self, config: &DecideConfig) -> Result<TrialSequence, Error> {
+        self.validate()?;
+        let stimuli: Vec<(Trial, u32)> = config
+            .stimuli()
+            .sorted_by_key(|stimulus| stimulus.name())
+            .map(|stimulus| {
+                let trial = Trial {
+                    stimulus: String::from(stimulus.name()),
+                    correct: stimulus.correct_response(),
+                };
+                (trial, stimulus.frequency())
+            })
+            .collect();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut trials = Vec::new();
+        for _ in 0..self.blocks {
+            let block = match self.order {
+                SequenceOrder::Block => stimuli
+                    .iter()
+                    .flat_map(|(trial, frequency)| {
+                        itertools::repeat_n(trial.clone(), *frequency as usize)
+                    })
+                    .collect(),
+                SequenceOrder::Gellermann => self.gellermann_block(&stimuli, &mut rng)?,
+            };
+            let arranged = (0..MAX_ATTEMPTS)
+                .find_map(|_| self.arrange(block.clone(), &trials, &mut rng))
+                .ok_or_else(|| {
+                    Error::InvalidSequence(format!(
+                        "could not order the trials with at most {} in a row with the same response",
+                        self.max_run
+                    ))
+                })?;
+            trials.extend(arranged);
+        }
+        Ok(TrialSequence {
+            settings: self.clone(),
+            trials,
+        })
+    }
+
+    /// `block_size` trials, split evenly between the correct responses
+    fn gellermann_block(
+        &self,
+        stimuli: &[(Trial, u32)],
+        rng: &mut StdRng,
+    ) -> Result<Vec<Trial>, Error> {
+        let by_response = stimuli
+            .iter()
+            .flat_map(|(trial, frequency)| itertools::repeat_n(trial, *frequency as usize))
+            .into_group_map_by(|trial| trial.correct);
+        if by_response.is_empty() || !self.block_size.is_multiple_of(by_response.len()) {
+            return Err(Error::InvalidSequence(format!(
+                "`block_size` must be a multiple of the number of correct responses ({})",
+                by_response.len()
+            )));
+        }
+        let per_response = self.block_size / by_response.len();
+        Ok(by_response
+            .into_iter()
+            .sorted_by_key(|(response, _)| *response)
+            .flat_map(|(_, pool)| {
+                (0..per_response)
+                    .map(|_| (*pool.choose(rng).unwrap()).clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    /// Randomly orders `block` to follow `previous`, or `None` if it ran into a
+    /// dead end where every remaining trial would make too long a run
+    fn arrange(
+        &self,
+        mut block: Vec<Trial>,
+        previous: &[Trial],
+        rng: &mut StdRng,
+    ) -> Option<Vec<Trial>> {
+        let mut arranged: Vec<Trial> = Vec::with_capacity(block.len());
+        while !block.is_empty() {
+            let mut recent = previous.iter().chain(arranged.iter()).rev();
+            let run = recent.next().map(|last| {
+                let length = 1 + recent.take_while(|t| t.correct == last.correct).count();
+                (last.correct, length)
+            });
+            let allowed: Vec<usize> = (0..block.len())
+                .filter(|&i| match run {
+                    Some((response, length)) => {
+                        block[i].correct != response || length < self.max_run
+                    }
+                    None => true,
+                })
+                .collect();
+            let &next = allowed.choose(rng)?;
+            arranged.push(block.swap_remove(next));
+        }
+        Some(arranged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DecideConfig {
+        let stimulus = |name, correct| {
+            let (left, right) = if correct == "left" {
+                (true, false)
+            } else {
+                (false, true)
+            };
+            format!(
+                r#"{{"name": "{}", "frequency": 1, "responses": {{
+                    "peck_left": {{"correct": {}}}, "peck_right": {{"correct": {}}}
+                }}}}"#,
+                name, left, right
+            )
+        };
+        serde_json::from_str(&format!(
+            r#"{{"parameters": {{}}, "stimulus_root": "/", "stimuli": [{}, {}, {}, {}]}}"#,
+            stimulus("a", "left"),
+            stimulus("b", "left"),
+            stimulus("c", "right"),
+            stimulus("d", "right"),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn run_length() {
+        for order in &["block", "gellermann"] {
+            let settings: SequenceConfig = serde_yaml::from_str(&format!(
+                "{{order: {}, blocks: 20, block_size: 6, max_run: 2, seed: 3}}",
+                order
+            ))
+            .unwrap();
+            let sequence = settings.generate(&config()).unwrap();
+            let longest_run = sequence
+                .trials
+                .iter()
+                .group_by(|trial| trial.correct)
+                .into_iter()
+                .map(|(_, run)| run.count())
+                .max();
+            assert_eq!(longest_run, Some(2));
+            let block_size = if *order == "block" { 4 } else { 6 };
+            for block in sequence.trials.chunks(block_size) {
+                let left = block
+                    .iter()
+                    .filter(|trial| trial.correct == Some(Response::PeckLeft))
+                    .count();
+                assert_eq!(left * 2, block_size);
+            }
+            assert_eq!(
+                settings.generate(&config()).unwrap().trials,
+                sequence.trials
+            );
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use super::{
+    make_configs_at, stimulus::StimuliConfig, AttributeLabel, ConfigWithParams, CorrectChoices,
+    Error, Experiment, StimulusAttribute,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Settings for an n-down/m-up staircase over the values of a numeric attribute
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StaircaseConfig {
+    attribute: AttributeLabel,
+    start: i32,
+    /// correct trials in a row before the task gets harder
+    #[serde(default = "one")]
+    down: usize,
+    /// incorrect trials in a row before the task gets easier
+    #[serde(default = "one")]
+    up: usize,
+    /// how many levels the task gets harder by
+    #[serde(default = "one")]
+    step_down: usize,
+    /// how many levels the task gets easier by
+    #[serde(default = "one")]
+    step_up: usize,
+    /// whether lower or higher values of the attribute are harder
+    #[serde(default)]
+    harder: Harder,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Harder {
+    #[default]
+    Lower,
+    Higher,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Harder,
+    Easier,
+}
+
+/// Where a staircase is, saved between sessions
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct StaircaseState {
+    pub level: i32,
+    /// the number of logged trials that have been read so far
+    pub trials: usize,
+    /// the number of trials read from each trial log, by its canonical path
+    #[serde(default)]
+    pub logs: BTreeMap<String, usize>,
+    pub reversals: usize,
+    correct_run: usize,
+    incorrect_run: usize,
+    last_step: Option<Step>,
+}
+
+/// One trial from a decide trial log; other fields in the log are ignored
+#[derive(Deserialize, Debug)]
+pub struct LoggedTrial {
+    pub stimulus: String,
+    pub correct: bool,
+}
+
+impl StaircaseConfig {
+    pub fn validate(&self, stimuli: &StimuliConfig, name_args: &[&str]) -> Result<(), Error> {
+        let values = stimuli.list_values(&self.attribute).ok_or_else(|| {
+            Error::InvalidStaircase(format!(
+                "{} is not an attribute under `stimuli`",
+                self.attribute
+            ))
+        })?;
+        if values.iter().any(|value| value.as_numeric().is_none()) {
+            return Err(Error::InvalidStaircase(format!(
+                "not all of the values of {} are numbers",
+                self.attribute
+            )));
+        }
+        if !values.contains(&&StimulusAttribute::from(self.start)) {
+            return Err(Error::InvalidStaircase(format!(
+                "`start` ({}) is not one of the values of {}",
+                self.start, self.attribute
+            )));
+        }
+        if !name_args.contains(&&self.attribute.to_string()[..]) {
+            return Err(Error::InvalidStaircase(format!(
+                "{} must be used in `name_format`, so that each config has one value of it",
+                self.attribute
+            )));
+        }
+        if [self.down, self.up, self.step_down, self.step_up].contains(&0) {
+            return Err(Error::InvalidStaircase(String::from(
+                "`down`, `up`, `step_down` and `step_up` must be at least 1",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn start(&self) -> StaircaseState {
+        StaircaseState {
+            level: self.start,
+            trials: 0,
+            logs: BTreeMap::new(),
+            reversals: 0,
+            correct_run: 0,
+            incorrect_run: 0,
+            last_step: None,
+        }
+    }
+
+    /// Moves the staircase through the trials in each named log that have not been
+    /// read yet, so each run can be given every log or just the new ones. Trials with
+    /// a stimulus from a different level, which were run before the level last
+    /// changed, are skipped.
+    pub fn update(
+        &self,
+        state: &mut StaircaseState,
+        experiment: &Experiment,
+        logs: &[(String, Vec<LoggedTrial>)],
+    ) -> Result<(), Error> {
+        for (name, trials) in logs {
+            let read = state.logs.get(name).copied().unwrap_or(0);
+            if trials.len() < read {
+                return Err(Error::TrialLogShortened(name.clone(), trials.len(), read));
+            }
+            self.step_through(state, experiment, &trials[read..]);
+            state.trials += trials.len() - read;
+            state.logs.insert(name.clone(), trials.len());
+        }
+        Ok(())
+    }
+
+    fn step_through(
+        &self,
+        state: &mut StaircaseState,
+        experiment: &Experiment,
+        trials: &[LoggedTrial],
+    ) {
+        let levels = self.levels(experiment);
+        let stimulus_levels: HashMap<String, i32> = experiment
+            .stimuli()
+            .into_iter()
+            .filter_map(|stimulus| {
+                let level = stimulus.attribute(&self.attribute)?.as_numeric()?;
+                Some((String::from(stimulus), level))
+            })
+            .collect();
+        for trial in trials {
+            if stimulus_levels.get(&trial.stimulus) != Some(&state.level) {
+                continue;
+            }
+            let step = if trial.correct {
+                state.correct_run += 1;
+                state.incorrect_run = 0;
+                Some(Step::Harder).filter(|_| state.correct_run >= self.down)
+            } else {
+                state.incorrect_run += 1;
+                state.correct_run = 0;
+                Some(Step::Easier).filter(|_| state.incorrect_run >= self.up)
+            };
+            if let Some(step) = step {
+                self.step(state, &levels, step);
+            }
+        }
+    }
+
+    fn step(&self, state: &mut StaircaseState, levels: &[i32], step: Step) {
+        let index = levels.iter().position(|&l| l == state.level).unwrap_or(0) as isize;
+        let towards_lower = (step == Step::Harder) == (self.harder == Harder::Lower);
+        let size = match step {
+            Step::Harder => self.step_down,
+            Step::Easier => self.step_up,
+        } as isize;
+        let index = if towards_lower {
+            index - size
+        } else {
+            index + size
+        };
+        state.level = levels[index.clamp(0, levels.len() as isize - 1) as usize];
+        if state.last_step.is_some_and(|last| last != step) {
+            state.reversals += 1;
+        }
+        state.last_step = Some(step);
+        state.correct_run = 0;
+        state.incorrect_run = 0;
+    }
+
+    /// The values of the attribute, from lowest to highest
+    fn levels(&self, experiment: &Experiment) -> Vec<i32> {
+        let mut levels: Vec<i32> = experiment
+            .list_attribute_values(&self.attribute)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(StimulusAttribute::as_numeric)
+            .collect();
+        levels.sort_unstable();
+        levels
+    }
+
+    /// The configs for the staircase's current level
+    pub fn configs<'a>(
+        &self,
+        experiment: &'a Experiment,
+        correct_choices: &CorrectChoices,
+        state: &StaircaseState,
+    ) -> Result<Vec<ConfigWithParams<'a>>, Error> {
+        let level = (self.attribute.clone(), StimulusAttribute::from(state.level));
+        make_configs_at(experiment, correct_choices, &[level])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_down_one_up() {
+        let experiment: Experiment = crate::test_experiment(
+            "
+            name_format: 'x{fg_db}'
+            staircase: {attribute: fg_db, start: 30, down: 2}
+            ",
+            "
+            format: '{a}_{fg_db}'
+            decisive_attribute: a
+            a:
+                values: [a, b]
+            fg_db:
+                values: [40, 10, 30, 20]
+            ",
+        )
+        .unwrap();
+        let settings = experiment.staircase().unwrap();
+        let mut state = settings.start();
+        let trial = |stimulus: &str, correct| LoggedTrial {
+            stimulus: String::from(stimulus),
+            correct,
+        };
+        let day1 = vec![
+            trial("a_30", true),
+            trial("b_30", true),
+            trial("a_30", false),
+        ];
+        let logs = vec![(String::from("day1"), day1)];
+        settings.update(&mut state, &experiment, &logs).unwrap();
+        // the last trial was run before the level changed
+        assert_eq!((state.level, state.reversals), (20, 0));
+        // given again, the trials of day1 are not read twice
+        let day2 = vec![trial("a_20", false), trial("a_30", true)];
+        let mut logs = logs;
+        logs.push((String::from("day2"), day2));
+        settings.update(&mut state, &experiment, &logs).unwrap();
+        assert_eq!((state.level, state.reversals), (30, 1));
+        // a run can be given just the new log
+        let day3 = vec![trial("b_30", true), trial("a_30", true)];
+        settings
+            .update(&mut state, &experiment, &[(String::from("day3"), day3)])
+            .unwrap();
+        assert_eq!((state.level, state.reversals), (20, 2));
+        assert_eq!(state.trials, 7);
+        let shortened = vec![(String::from("day2"), vec![trial("a_20", false)])];
+        assert!(matches!(
+            settings.update(&mut state, &experiment, &shortened),
+            Err(Error::TrialLogShortened(..))
+        ));
+        let correct_choices = CorrectChoices::random(&experiment, 1).unwrap();
+        let configs = settings
+            .configs(&experiment, &correct_choices, &state)
+            .unwrap();
+        assert_eq!(configs.len(), 2);
+        assert!(configs
+            .iter()
+            .all(|(_, attributes)| attributes["fg_db"] == StimulusAttribute::from(20)));
+    }
+}
@@ -1,9 +1,17 @@
-use core::cmp::{Ordering, PartialOrd};
+use super::{derived::DerivedAttribute, format_args, validate::Problem, Error};
 use dynfmt::{curly::SimpleCurlyFormat, Format};
 use itertools::Itertools;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_diff::SerdeDiff;
-use std::{borrow::Borrow, collections::HashMap, fmt, iter};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    convert::{Infallible, TryFrom},
+    fmt, iter,
+    path::PathBuf,
+    str::FromStr,
+};
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(into = "String")]
@@ -21,10 +29,19 @@ impl<'a> Stimulus<'a> {
         Stimulus { attributes, config }
     }
 
-    pub fn decisive_attribute(&self) -> &StimulusAttribute {
-        self.attributes
-            .get(&self.config.decisive_attribute)
-            .expect("StimuliConfig does not contain decisive_attribute")
+    pub fn decisive_value(&self) -> DecisiveValue {
+        DecisiveValue(
+            self.config
+                .decisive_attributes
+                .iter()
+                .map(|label| {
+                    self.attributes
+                        .get(label)
+                        .expect("StimuliConfig does not contain decisive_attribute")
+                        .clone()
+                })
+                .collect(),
+        )
     }
 
     pub fn category(&self) -> Option<&StimulusAttribute> {
@@ -35,17 +52,29 @@ impl<'a> Stimulus<'a> {
         })
     }
 
+    pub fn attribute(&self, label: &AttributeLabel) -> Option<&StimulusAttribute> {
+        self.attributes.get(label)
+    }
+
+    /// The stimulus' attributes, such as `{fg_db: 30, foreground: g29wxi4q}`
+    pub fn describe(&self) -> String {
+        let attributes = self
+            .attributes
+            .iter()
+            .sorted_by_key(|(label, _)| *label)
+            .map(|(label, value)| format!("{}: {}", label, value));
+        format!("{{{}}}", attributes.format(", "))
+    }
+
+    pub fn matches_filter(&self, filter: &AttributeFilter) -> bool {
+        filter.matches(&self.attributes)
+    }
+
     pub fn matches(&self, (label, attribute): &(&AttributeLabel, &StimulusAttribute)) -> bool {
-        let inclusive_less_than = self.config.values.get(*label).unwrap().inclusive_less_than;
+        let matching = self.config.matching(label);
         self.attributes
             .get(*label)
-            .map(|a| {
-                if inclusive_less_than {
-                    a <= attribute
-                } else {
-                    a == *attribute
-                }
-            })
+            .map(|a| matching.matches(a, attribute))
             .unwrap_or(false)
     }
 }
@@ -59,16 +88,72 @@ impl<'a> From<Stimulus<'a>> for String {
     }
 }
 
-#[derive(Serialize, Deserialize, SerdeDiff, PartialEq, Hash, Eq, Clone, Debug)]
+/// Numbers are ordered by value and come before text, which is ordered alphabetically
+#[derive(
+    Serialize,
+    Deserialize,
+    SerdeDiff,
+    JsonSchema,
+    PartialEq,
+    Hash,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Debug,
+)]
 pub struct StimulusAttribute(AttributeKind);
 
+impl StimulusAttribute {
+    pub fn as_numeric(&self) -> Option<i32> {
+        match self.0 {
+            Numeric(num) => Some(num),
+            Text(_) => None,
+        }
+    }
+}
+
 impl From<&str> for StimulusAttribute {
     fn from(attribute: &str) -> Self {
         StimulusAttribute(Text(attribute.into()))
     }
 }
 
-#[derive(Serialize, Deserialize, SerdeDiff, PartialEq, Hash, Eq, Clone, Debug)]
+/// Reads numbers as numeric attributes and anything else as text, like the untagged
+/// deserialization. Only text that is written the way the number would be is read as
+/// one, so that `0042` or `+5` stay text, as they would if quoted in YAML.
+impl FromStr for StimulusAttribute {
+    type Err = Infallible;
+
+    fn from_str(attribute: &str) -> Result<Self, Self::Err> {
+        Ok(attribute
+            .parse::<i32>()
+            .ok()
+            .filter(|num| num.to_string() == attribute)
+            .map(|num| StimulusAttribute(Numeric(num)))
+            .unwrap_or_else(|| StimulusAttribute::from(attribute)))
+    }
+}
+
+impl From<i32> for StimulusAttribute {
+    fn from(attribute: i32) -> Self {
+        StimulusAttribute(Numeric(attribute))
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    SerdeDiff,
+    JsonSchema,
+    PartialEq,
+    Hash,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Debug,
+)]
 #[serde(untagged)]
 enum AttributeKind {
     Numeric(i32),
@@ -77,17 +162,6 @@ enum AttributeKind {
 
 use AttributeKind::*;
 
-impl PartialOrd<StimulusAttribute> for StimulusAttribute {
-    fn partial_cmp(&self, other: &StimulusAttribute) -> Option<Ordering> {
-        match (self, other) {
-            (&StimulusAttribute(Numeric(lhs)), &StimulusAttribute(Numeric(rhs))) => {
-                lhs.partial_cmp(&rhs)
-            }
-            _ => panic!("comparing a numeric attribute with a non-numeric attribute"),
-        }
-    }
-}
-
 impl fmt::Display for StimulusAttribute {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.0 {
@@ -97,9 +171,27 @@ impl fmt::Display for StimulusAttribute {
     }
 }
 
-#[derive(Serialize, Deserialize, SerdeDiff, PartialEq, Hash, Eq, Clone, Debug)]
+#[derive(
+    Serialize,
+    Deserialize,
+    SerdeDiff,
+    JsonSchema,
+    PartialEq,
+    Hash,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Debug,
+)]
 pub struct AttributeLabel(String);
 
+impl fmt::Display for AttributeLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl Borrow<str> for AttributeLabel {
     fn borrow(&self) -> &str {
         &self.0
@@ -112,33 +204,473 @@ impl From<&str> for AttributeLabel {
     }
 }
 
-#[derive(Serialize, Deserialize, SerdeDiff, Debug)]
+/// How the stimuli in a config are compared against the value `x` of a
+/// held-constant attribute.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeMatch {
+    Equal,
+    LessThan,
+    AtMost,
+    GreaterThan,
+    AtLeast,
+    /// the closed interval `[x + lower, x + upper]`
+    Between(i32, i32),
+}
+
+impl AttributeMatch {
+    pub fn matches(self, value: &StimulusAttribute, reference: &StimulusAttribute) -> bool {
+        use AttributeMatch::*;
+        match self {
+            Equal => value == reference,
+            LessThan => value < reference,
+            AtMost => value <= reference,
+            GreaterThan => value > reference,
+            AtLeast => value >= reference,
+            Between(lower, upper) => match (value.as_numeric(), reference.as_numeric()) {
+                (Some(value), Some(reference)) => {
+                    reference + lower <= value && value <= reference + upper
+                }
+                _ => panic!("comparing a numeric attribute with a non-numeric attribute"),
+            },
+        }
+    }
+
+    fn is_ordered(self) -> bool {
+        self != AttributeMatch::Equal
+    }
+}
+
+#[derive(Serialize, Deserialize, SerdeDiff, JsonSchema, Default, Debug)]
 struct AttributeConfig {
+    #[serde(default)]
     values: Vec<StimulusAttribute>,
     #[serde(default)]
     inclusive_less_than: bool,
+    #[serde(rename = "match", default)]
+    #[serde_diff(opaque)]
+    matching: Option<AttributeMatch>,
+}
+
+impl AttributeConfig {
+    fn matching(&self) -> AttributeMatch {
+        match (self.matching, self.inclusive_less_than) {
+            (Some(matching), _) => matching,
+            (None, true) => AttributeMatch::AtMost,
+            (None, false) => AttributeMatch::Equal,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(value) => std::slice::from_ref(value),
+            OneOrMany::Many(values) => values,
+        }
+    }
+
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// The values of the decisive attributes of a stimulus, which decide its correct response.
+///
+/// A single value is written as-is, and several are written as one string
+/// separated by `", "`, so they can be used as keys in `correct_choices.yml`.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Debug)]
+pub struct DecisiveValue(Vec<StimulusAttribute>);
+
+const DECISIVE_VALUE_SEPARATOR: &str = ", ";
+
+impl From<StimulusAttribute> for DecisiveValue {
+    fn from(attribute: StimulusAttribute) -> Self {
+        DecisiveValue(vec![attribute])
+    }
+}
+
+impl iter::FromIterator<StimulusAttribute> for DecisiveValue {
+    fn from_iter<I: IntoIterator<Item = StimulusAttribute>>(attributes: I) -> Self {
+        DecisiveValue(attributes.into_iter().collect())
+    }
+}
+
+impl fmt::Display for DecisiveValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.iter().format(DECISIVE_VALUE_SEPARATOR))
+    }
+}
+
+impl Serialize for DecisiveValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0[..] {
+            [attribute] => attribute.serialize(serializer),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+/// Written as a single value, a list, or one string separated by `", "`
+impl JsonSchema for DecisiveValue {
+    fn schema_name() -> String {
+        String::from("DecisiveValue")
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        OneOrMany::<StimulusAttribute>::json_schema(gen)
+    }
+}
+
+/// A single string is read as it is; whether it holds several values separated by
+/// `", "` depends on the experiment, so it is split by `DecisiveValue::split`
+impl<'de> Deserialize<'de> for DecisiveValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let attributes = OneOrMany::<StimulusAttribute>::deserialize(deserializer)?;
+        Ok(DecisiveValue(attributes.into_vec()))
+    }
+}
+
+impl DecisiveValue {
+    /// Reads a single string such as `"song, 30"` as the values of `n_attributes`
+    /// decisive attributes. With one decisive attribute it is kept whole, since
+    /// `"song, loud"` is then a single value. It is an error if the value does not
+    /// have one part for each decisive attribute.
+    pub fn split(self, n_attributes: usize) -> Result<Self, Error> {
+        let value = match &self.0[..] {
+            [StimulusAttribute(Text(text))] if n_attributes > 1 => text
+                .split(DECISIVE_VALUE_SEPARATOR)
+                .map(|attribute| attribute.parse().unwrap())
+                .collect(),
+            _ => self,
+        };
+        if value.0.len() == n_attributes {
+            Ok(value)
+        } else {
+            let n_parts = value.0.len();
+            Err(Error::DecisiveValueParts(value, n_parts, n_attributes))
+        }
+    }
+}
+
+/// Matches the stimuli that have one of the listed values for every listed attribute
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
+pub struct AttributeFilter(BTreeMap<AttributeLabel, OneOrMany<StimulusAttribute>>);
+
+impl AttributeFilter {
+    fn matches(&self, attributes: &HashMap<AttributeLabel, StimulusAttribute>) -> bool {
+        self.0.iter().all(|(label, values)| {
+            attributes
+                .get(label)
+                .map(|value| values.as_slice().contains(value))
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = &AttributeLabel> {
+        self.0.keys()
+    }
+}
+
+impl fmt::Display for AttributeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms = self
+            .0
+            .iter()
+            .sorted_by_key(|(label, _)| *label)
+            .map(|(label, values)| format!("{}: {}", label, values.as_slice().iter().join(" | ")));
+        write!(f, "{{{}}}", terms.format(", "))
+    }
+}
+
+type AttributeSet = HashMap<AttributeLabel, StimulusAttribute>;
+
+/// An explicit list of stimuli, either inline or in a CSV file with one column per attribute
+#[derive(Deserialize, JsonSchema, Debug)]
+#[serde(untagged)]
+enum StimulusTable {
+    Inline(Vec<AttributeSet>),
+    File(PathBuf),
+}
+
+impl StimulusTable {
+    fn rows(self) -> Result<Vec<AttributeSet>, Error> {
+        match self {
+            StimulusTable::Inline(rows) => Ok(rows),
+            StimulusTable::File(path) => {
+                let table_error = |e| Error::StimulusTable(path.display().to_string(), e);
+                let mut reader = csv::Reader::from_path(&path).map_err(table_error)?;
+                let labels: Vec<AttributeLabel> = reader
+                    .headers()
+                    .map_err(table_error)?
+                    .iter()
+                    .map(|label| AttributeLabel::from(label.trim()))
+                    .collect();
+                reader
+                    .records()
+                    .map(|record| {
+                        let record = record.map_err(table_error)?;
+                        Ok(labels
+                            .iter()
+                            .cloned()
+                            .zip(record.iter().map(str::trim))
+                            .filter(|(_, value)| !value.is_empty())
+                            .map(|(label, value)| (label, value.parse().unwrap()))
+                            .collect())
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, SerdeDiff, Debug)]
+#[serde(try_from = "UnvalidatedStimuliConfig")]
 pub struct StimuliConfig {
     format: String,
-    decisive_attribute: AttributeLabel,
+    decisive_attributes: Vec<AttributeLabel>,
     category: Option<AttributeLabel>,
+    #[serde_diff(skip)]
+    exclude: Vec<AttributeFilter>,
+    #[serde_diff(skip)]
+    include_only: Vec<AttributeFilter>,
+    /// every stimulus, before `exclude` and `include_only` are applied
+    #[serde_diff(skip)]
+    rows: Vec<AttributeSet>,
+    values: BTreeMap<AttributeLabel, AttributeConfig>,
+    #[serde_diff(skip)]
+    derived: Vec<AttributeLabel>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UnvalidatedStimuliConfig {
+    format: String,
+    decisive_attribute: OneOrMany<AttributeLabel>,
+    category: Option<AttributeLabel>,
+    #[serde(default)]
+    exclude: Vec<AttributeFilter>,
+    #[serde(default)]
+    include_only: Vec<AttributeFilter>,
+    table: Option<StimulusTable>,
+    #[serde(default)]
+    derived: BTreeMap<AttributeLabel, DerivedAttribute>,
     #[serde(flatten)]
-    values: HashMap<AttributeLabel, AttributeConfig>,
+    values: BTreeMap<AttributeLabel, AttributeConfig>,
+}
+
+impl JsonSchema for StimuliConfig {
+    fn schema_name() -> String {
+        String::from("StimuliConfig")
+    }
+
+    /// Every other key is an attribute, which schemars leaves out of flattened maps
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = UnvalidatedStimuliConfig::json_schema(gen).into_object();
+        schema.object().additional_properties =
+            Some(Box::new(gen.subschema_for::<AttributeConfig>()));
+        Schema::Object(schema)
+    }
+}
+
+impl TryFrom<UnvalidatedStimuliConfig> for StimuliConfig {
+    type Error = Error;
+
+    fn try_from(config: UnvalidatedStimuliConfig) -> Result<Self, Self::Error> {
+        config.build().map_err(|problem| problem.error)
+    }
+}
+
+impl UnvalidatedStimuliConfig {
+    /// Lists or combines the stimuli, and computes the derived attributes
+    pub fn build(self) -> Result<StimuliConfig, Problem> {
+        let UnvalidatedStimuliConfig {
+            format,
+            decisive_attribute,
+            category,
+            exclude,
+            include_only,
+            table,
+            derived,
+            mut values,
+        } = self;
+        let mut rows = match table {
+            Some(table) => {
+                let inline = matches!(table, StimulusTable::Inline(_));
+                let rows = table.rows().map_err(|e| Problem::new("stimuli.table", e))?;
+                // in table mode, each attribute's values are the ones that appear in its column
+                if let Some((label, _)) =
+                    values.iter().find(|(_, config)| !config.values.is_empty())
+                {
+                    return Err(Problem::new(
+                        format!("stimuli.{}.values", label),
+                        Error::ValuesWithTable(label.to_string()),
+                    ));
+                }
+                for label in rows.iter().flat_map(HashMap::keys) {
+                    values
+                        .entry(label.clone())
+                        .or_insert_with(AttributeConfig::default);
+                }
+                for (i, row) in rows.iter().enumerate() {
+                    if let Some(label) = values.keys().find(|label| !row.contains_key(*label)) {
+                        let path = if inline {
+                            format!("stimuli.table[{}]", i)
+                        } else {
+                            String::from("stimuli.table")
+                        };
+                        let error = Error::IncompleteTableRow(i + 1, label.to_string());
+                        return Err(Problem::new(path, error));
+                    }
+                }
+                rows
+            }
+            None => values
+                .iter()
+                .map(|(label, config)| {
+                    iter::repeat(label.clone()).zip(config.values.iter().cloned())
+                })
+                .multi_cartesian_product()
+                .map(|attributes| attributes.into_iter().collect())
+                .collect(),
+        };
+        for (label, derivation) in derived.iter() {
+            let path = format!("stimuli.derived.{}", label);
+            derivation
+                .validate(label)
+                .map_err(|e| Problem::new(&path, e))?;
+            if values.contains_key(label) {
+                return Err(Problem::new(
+                    path,
+                    Error::DuplicateAttribute(label.to_string()),
+                ));
+            }
+            if let Some(reference) = derivation
+                .references()
+                .into_iter()
+                .find(|reference| !values.contains_key(*reference))
+            {
+                let error =
+                    Error::UnknownAttributeInDerived(label.to_string(), reference.to_string());
+                return Err(Problem::new(path, error));
+            }
+        }
+        // a derived attribute that can't be computed is only an error for a stimulus that
+        // the `include_only` and `exclude` rules keep
+        for row in rows.iter_mut() {
+            let mut failure = None;
+            for (label, derivation) in derived.iter() {
+                match derivation.evaluate(label, row) {
+                    Ok(value) => {
+                        row.insert(label.clone(), value);
+                    }
+                    Err(e) => {
+                        let path = format!("stimuli.derived.{}", label);
+                        failure.get_or_insert(Problem::new(path, e));
+                    }
+                }
+            }
+            match failure {
+                Some(problem) if is_kept(row, &include_only, &exclude) => return Err(problem),
+                _ => {}
+            }
+        }
+        let derived_labels: Vec<_> = derived.keys().cloned().collect();
+        let derived_configs = derived
+            .into_iter()
+            .map(|(label, derivation)| {
+                let config = AttributeConfig {
+                    matching: derivation.matching,
+                    ..AttributeConfig::default()
+                };
+                (label, config)
+            })
+            .collect::<Vec<_>>();
+        values.extend(derived_configs);
+        for (label, config) in values.iter_mut() {
+            if config.values.is_empty() {
+                // derived values that only removed stimuli have are left out
+                config.values = rows
+                    .iter()
+                    .filter(|row| {
+                        !derived_labels.contains(label) || is_kept(row, &include_only, &exclude)
+                    })
+                    .map(|row| row[label].clone())
+                    .unique()
+                    .collect();
+            }
+        }
+        Ok(StimuliConfig {
+            format,
+            decisive_attributes: decisive_attribute.into_vec(),
+            category,
+            exclude,
+            include_only,
+            rows,
+            values,
+            derived: derived_labels,
+        })
+    }
+}
+
+/// Whether the `include_only` and `exclude` rules keep a stimulus with `attributes`
+fn is_kept(
+    attributes: &AttributeSet,
+    include_only: &[AttributeFilter],
+    exclude: &[AttributeFilter],
+) -> bool {
+    (include_only.is_empty() || include_only.iter().any(|filter| filter.matches(attributes)))
+        && !exclude.iter().any(|filter| filter.matches(attributes))
 }
 
 impl StimuliConfig {
     pub fn stimuli(&self) -> Vec<Stimulus<'_>> {
-        self.values
-            .iter()
-            .map(|(label, config)| iter::repeat(label.clone()).zip(config.values.iter().cloned()))
-            .multi_cartesian_product()
-            .map(|attributes| {
-                let attributes: HashMap<_, _> = attributes.into_iter().collect();
-                Stimulus::new(attributes, self)
-            })
-            .collect()
+        self.pruned().0
+    }
+
+    /// The number of stimuli removed by each `include_only` and `exclude` rule
+    pub fn filter_report(&self) -> Vec<(String, usize)> {
+        self.pruned().1
+    }
+
+    fn pruned(&self) -> (Vec<Stimulus<'_>>, Vec<(String, usize)>) {
+        let mut attribute_sets = self.rows.clone();
+        let mut report = Vec::new();
+        if !self.include_only.is_empty() {
+            let before = attribute_sets.len();
+            attribute_sets.retain(|attributes| {
+                self.include_only
+                    .iter()
+                    .any(|filter| filter.matches(attributes))
+            });
+            let rule = format!("include_only [{}]", self.include_only.iter().format(", "));
+            report.push((rule, before - attribute_sets.len()));
+        }
+        for filter in self.exclude.iter() {
+            let before = attribute_sets.len();
+            attribute_sets.retain(|attributes| !filter.matches(attributes));
+            report.push((format!("exclude {}", filter), before - attribute_sets.len()));
+        }
+        let stimuli = attribute_sets
+            .into_iter()
+            .map(|attributes| Stimulus::new(attributes, self))
+            .collect();
+        (stimuli, report)
     }
 
     pub fn attribute_labels(&self) -> impl Iterator<Item = &AttributeLabel> {
@@ -152,18 +684,136 @@ impl StimuliConfig {
     }
 
     pub fn label_by_str<'a>(&'a self, label: &str) -> Option<&'a AttributeLabel> {
-        Self::attribute_label_by_str(&self.values, label)
+        self.values.get_key_value(label).map(|(label, _)| label)
+    }
+
+    pub fn decisive_attributes(&self) -> &[AttributeLabel] {
+        &self.decisive_attributes
     }
 
-    fn attribute_label_by_str<'a, T>(
-        attributes: &'a HashMap<AttributeLabel, T>,
-        label: &str,
-    ) -> Option<&'a AttributeLabel> {
-        attributes.keys().find(|AttributeLabel(l)| l == label)
+    pub fn format(&self) -> &str {
+        &self.format
     }
 
-    pub fn decisive_attribute(&self) -> &AttributeLabel {
-        &self.decisive_attribute
+    fn matching(&self, label: &AttributeLabel) -> AttributeMatch {
+        self.values
+            .get(label)
+            .map(AttributeConfig::matching)
+            .unwrap_or(AttributeMatch::Equal)
+    }
+
+    /// Stimuli that `format` gives the same name, which would overwrite each other in a config
+    fn name_collisions(&self) -> Vec<Problem> {
+        self.stimuli()
+            .into_iter()
+            .map(|stimulus| (String::from(stimulus.clone()), stimulus))
+            .into_group_map()
+            .into_iter()
+            .filter(|(_, stimuli)| stimuli.len() > 1)
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(name, stimuli)| {
+                let stimuli = stimuli.iter().map(Stimulus::describe).join(", ");
+                Problem::new(
+                    "stimuli.format",
+                    Error::StimulusNameCollision { name, stimuli },
+                )
+            })
+            .collect()
+    }
+
+    fn attribute_path(&self, label: &AttributeLabel) -> String {
+        if self.derived.contains(label) {
+            format!("stimuli.derived.{}", label)
+        } else {
+            format!("stimuli.{}", label)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem.error),
+            None => Ok(()),
+        }
+    }
+
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (label, config) in self.values.iter().sorted_by_key(|(label, _)| *label) {
+            let path = self.attribute_path(label);
+            if config.inclusive_less_than && config.matching.is_some() {
+                problems.push(Problem::new(
+                    format!("{}.match", path),
+                    Error::ConflictingMatchRules(label.to_string()),
+                ));
+            }
+            if config.matching().is_ordered()
+                && config.values.iter().any(|v| v.as_numeric().is_none())
+            {
+                problems.push(Problem::new(
+                    format!("{}.match", path),
+                    Error::NonNumericComparison(label.to_string()),
+                ));
+            }
+            for (i, value) in config.values.iter().enumerate() {
+                if config.values[..i].contains(value) {
+                    problems.push(Problem::new(
+                        format!("{}.values[{}]", path, i),
+                        Error::DuplicateValue(label.to_string(), value.clone()),
+                    ));
+                }
+            }
+        }
+        match format_args(&self.format) {
+            Ok(args) => problems.extend(
+                args.into_iter()
+                    .filter(|arg| self.label_by_str(arg).is_none())
+                    .map(|arg| {
+                        Problem::new(
+                            "stimuli.format",
+                            Error::UnknownAttributeInFormat(arg.into()),
+                        )
+                    }),
+            ),
+            Err(e) => problems.push(Problem::new("stimuli.format", e)),
+        }
+        if let Some(category) = self.category.as_ref() {
+            if !self.values.contains_key(category) {
+                problems.push(Problem::new(
+                    "stimuli.category",
+                    Error::CategoryNotFound(category.to_string()),
+                ));
+            }
+        }
+        let rules = self
+            .include_only
+            .iter()
+            .enumerate()
+            .map(|(i, filter)| (format!("stimuli.include_only[{}]", i), filter))
+            .chain(
+                self.exclude
+                    .iter()
+                    .enumerate()
+                    .map(|(i, filter)| (format!("stimuli.exclude[{}]", i), filter)),
+            );
+        for (path, filter) in rules {
+            for label in filter
+                .labels()
+                .filter(|label| !self.values.contains_key(*label))
+            {
+                problems.push(Problem::new(
+                    &path,
+                    Error::UnknownAttributeInRule(label.to_string()),
+                ));
+            }
+        }
+        if self.stimuli().is_empty() {
+            problems.push(Problem::new("stimuli", Error::NoStimuli));
+        }
+        if problems.is_empty() {
+            // names can only be formatted once the other problems are fixed
+            problems.extend(self.name_collisions());
+        }
+        problems
     }
 }
 
@@ -178,17 +828,260 @@ mod tests {
                 .into_iter()
                 .map(|(k, v)| (AttributeLabel::from(k), StimulusAttribute::from(v)))
                 .collect();
-        let values: HashMap<AttributeLabel, AttributeConfig> = HashMap::new();
+        let values: BTreeMap<AttributeLabel, AttributeConfig> = BTreeMap::new();
         let format = String::from("{a} {b}");
-        let decisive_attribute = AttributeLabel::from("a");
-        assert!(attributes.get("a").is_some());
+        let decisive_attributes = vec![AttributeLabel::from("a")];
+        assert!(attributes.contains_key("a"));
         let config = StimuliConfig {
             format,
-            decisive_attribute,
+            decisive_attributes,
             values,
             category: None,
+            exclude: Vec::new(),
+            include_only: Vec::new(),
+            rows: Vec::new(),
+            derived: Vec::new(),
         };
         let stim = Stimulus::new(attributes, &config);
         assert_eq!(serde_json::to_string(&stim).unwrap(), "\"hi hello\"");
     }
+
+    #[test]
+    fn match_operators() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}-{b}'
+            decisive_attribute: a
+            a:
+                values: [10, 20, 30]
+                match: at_least
+            b:
+                values: [10, 20, 30]
+                match:
+                    between: [-10, 0]
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let (a, b) = (AttributeLabel::from("a"), AttributeLabel::from("b"));
+        let twenty = StimulusAttribute::from(20);
+        let matching = |label| {
+            config
+                .stimuli()
+                .into_iter()
+                .filter(|s| s.matches(&(label, &twenty)))
+                .count()
+        };
+        // 20 and 30 for `a`, crossed with three values of `b`
+        assert_eq!(matching(&a), 6);
+        // 10 and 20 for `b`
+        assert_eq!(matching(&b), 6);
+    }
+
+    #[test]
+    fn ordered_match_requires_numbers() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}'
+            decisive_attribute: a
+            a:
+                values: [x, y]
+                match: less_than
+            ",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(Error::NonNumericComparison(_))
+        ));
+    }
+
+    #[test]
+    fn exclusion_rules() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}_{b}'
+            decisive_attribute: a
+            include_only:
+                - {a: [x, y]}
+            exclude:
+                - {a: x, b: 30}
+                - {b: 40}
+            a:
+                values: [x, y, z]
+            b:
+                values: [30, 40]
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let mut names: Vec<String> = config.stimuli().into_iter().map(String::from).collect();
+        names.sort();
+        assert_eq!(names, vec!["y_30"]);
+        let removed: Vec<usize> = config.filter_report().into_iter().map(|(_, n)| n).collect();
+        assert_eq!(removed, vec![2, 1, 2]);
+    }
+
+    #[test]
+    fn stimulus_table() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}_{b}'
+            decisive_attribute: a
+            table:
+                - {a: x, b: 30}
+                - {a: x, b: 40}
+                - {a: y, b: 40}
+            b:
+                match: at_least
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let names: Vec<String> = config.stimuli().into_iter().map(String::from).collect();
+        assert_eq!(names, vec!["x_30", "x_40", "y_40"]);
+        let b = AttributeLabel::from("b");
+        let b_values = config.list_values(&b).unwrap();
+        assert_eq!(
+            b_values,
+            vec![&StimulusAttribute::from(30), &StimulusAttribute::from(40)]
+        );
+        assert_eq!(config.matching(&b), AttributeMatch::AtLeast);
+    }
+
+    #[test]
+    fn text_that_looks_like_a_number() {
+        let parse = |text: &str| text.parse::<StimulusAttribute>().unwrap();
+        assert_eq!(parse("42"), StimulusAttribute::from(42));
+        assert_eq!(parse("-7"), StimulusAttribute::from(-7));
+        assert_eq!(parse("0042"), StimulusAttribute::from("0042"));
+        assert_eq!(parse("+5"), StimulusAttribute::from("+5"));
+    }
+
+    #[test]
+    fn derived_attributes() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{family}_{fg}_{snr}'
+            decisive_attribute: fg
+            derived:
+                snr:
+                    expr: fg_db - bg_db
+                    match: at_least
+                family:
+                    lookup: fg
+                    map: {x: songs}
+                    default: calls
+            exclude:
+                - {snr: 0}
+            fg:
+                values: [x, y]
+            fg_db:
+                values: [50, 60]
+            bg_db:
+                values: [50]
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let mut names: Vec<String> = config.stimuli().into_iter().map(String::from).collect();
+        names.sort();
+        assert_eq!(names, vec!["calls_y_10", "songs_x_10"]);
+        let snr = AttributeLabel::from("snr");
+        assert_eq!(config.matching(&snr), AttributeMatch::AtLeast);
+        // 0 is only the value of excluded stimuli
+        assert_eq!(config.list_values(&snr).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn derived_attribute_of_excluded_stimulus() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{family}_{fg}'
+            decisive_attribute: fg
+            derived:
+                family:
+                    lookup: fg
+                    map: {x: songs}
+            exclude:
+                - {fg: y}
+            fg:
+                values: [x, y]
+            ",
+        )
+        .unwrap();
+        let names: Vec<String> = config.stimuli().into_iter().map(String::from).collect();
+        assert_eq!(names, vec!["songs_x"]);
+        let family = AttributeLabel::from("family");
+        assert_eq!(config.list_values(&family).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn decisive_values() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}_{b}'
+            decisive_attribute: [a, b]
+            a:
+                values: [x]
+            b:
+                values: [30]
+            ",
+        )
+        .unwrap();
+        let value = config.stimuli()[0].decisive_value();
+        assert_eq!(serde_yaml::to_string(&value).unwrap(), "---\n\"x, 30\"\n");
+        let parsed: DecisiveValue = serde_yaml::from_str("x, 30").unwrap();
+        assert_eq!(parsed.clone().split(2).unwrap(), value);
+        // with one decisive attribute, a value can contain the separator
+        assert_eq!(
+            parsed.clone().split(1).unwrap(),
+            StimulusAttribute::from("x, 30").into()
+        );
+        assert!(matches!(
+            parsed.split(3),
+            Err(Error::DecisiveValueParts(_, 2, 3))
+        ));
+        let listed: DecisiveValue = serde_yaml::from_str("[x, 30]").unwrap();
+        assert_eq!(listed, value);
+    }
+
+    #[test]
+    fn name_collisions() {
+        let config: StimuliConfig = serde_yaml::from_str(
+            "
+            format: '{a}'
+            decisive_attribute: a
+            a:
+                values: [x, y]
+            b:
+                values: [30, 40]
+            ",
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert_eq!(problems.len(), 2, "one problem per colliding name");
+        match &problems[0].error {
+            Error::StimulusNameCollision { name, stimuli } => {
+                assert_eq!(name, "x");
+                assert!(stimuli.contains("{a: x, b: 30}"));
+                assert!(stimuli.contains("{a: x, b: 40}"));
+            }
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn incomplete_table_row() {
+        let config: Result<StimuliConfig, _> = serde_yaml::from_str(
+            "
+            format: '{a}_{b}'
+            decisive_attribute: a
+            table:
+                - {a: x, b: 30}
+                - {a: y}
+            ",
+        );
+        assert!(config.is_err());
+    }
 }
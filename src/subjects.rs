@@ -0,0 +1,150 @@
+use super::{CorrectChoices, Error, Experiment};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The subjects run on an experiment. Every subject has the same correct choices,
+/// and the inversion assigned to each one counterbalances them across the cohort.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SubjectRegistry {
+    /// the correct choices every subject shares, made when the first one is registered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correct_choices: Option<CorrectChoices>,
+    /// the seed the shared correct choices were randomly assigned with, unless
+    /// choices were assigned to new values later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    subjects: BTreeMap<String, Subject>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Subject {
+    /// whether the subject is trained on the inverted configs, which have the
+    /// opposite of the shared correct choices
+    pub inverted: bool,
+    /// the registry's shared correct choices
+    pub correct_choices: CorrectChoices,
+    /// the row of the cohort's balanced Latin square that orders the subject's sets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<usize>,
+}
+
+impl SubjectRegistry {
+    pub fn get(&self, id: &str) -> Option<&Subject> {
+        self.subjects.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Subject> {
+        self.subjects.get_mut(id)
+    }
+
+    pub fn subjects(&self) -> impl Iterator<Item = (&String, &Subject)> {
+        self.subjects.iter()
+    }
+
+    /// The correct choices every subject shares, if any subject is registered
+    pub fn correct_choices_mut(&mut self) -> Option<&mut CorrectChoices> {
+        self.correct_choices.as_mut()
+    }
+
+    /// Records that the shared correct choices were assigned to new values, giving
+    /// every subject the new choices. The seed no longer gives every assignment.
+    pub fn extended(&mut self) {
+        self.seed = None;
+        if let Some(correct_choices) = self.correct_choices.as_ref() {
+            for subject in self.subjects.values_mut() {
+                subject.correct_choices = correct_choices.clone();
+            }
+        }
+    }
+
+    /// Adds a subject with the shared correct choices, randomly assigning them if it
+    /// is the first subject. The subject is given whichever inversion fewer subjects
+    /// have, so half the cohort is trained on the opposite choices. A subject that is
+    /// already registered is left as it is.
+    pub fn register(&mut self, id: &str, experiment: &Experiment) -> Result<&Subject, Error> {
+        let mut rng = thread_rng();
+        let correct_choices = match self.correct_choices.as_ref() {
+            Some(correct_choices) => correct_choices.clone(),
+            None => {
+                let seed = rng.gen();
+                let correct_choices = CorrectChoices::random(experiment, seed)?;
+                self.seed = Some(seed);
+                self.correct_choices = Some(correct_choices.clone());
+                correct_choices
+            }
+        };
+        let n_inverted = self.subjects.values().filter(|s| s.inverted).count();
+        let n_normal = self.subjects.len() - n_inverted;
+        let inverted = if n_inverted == n_normal {
+            rng.gen()
+        } else {
+            n_inverted < n_normal
+        };
+        let subject = Subject {
+            inverted,
+            correct_choices,
+            sequence: None,
+        };
+        Ok(self.subjects.entry(String::from(id)).or_insert(subject))
+    }
+
+    /// Gives each of the registered subjects `ids` that does not have one yet a row of
+    /// a balanced Latin square with `n_rows` rows, choosing the row fewest subjects
+    /// with the same inversion have, so both inversions are balanced across orders
+    pub fn assign_sequences(&mut self, ids: &[&str], n_rows: usize) {
+        for id in ids {
+            let inverted = match self.subjects.get(*id) {
+                Some(subject) if subject.sequence.is_none() => subject.inverted,
+                _ => continue,
+            };
+            let mut counts = vec![0; n_rows];
+            for subject in self.subjects.values().filter(|s| s.inverted == inverted) {
+                if let Some(sequence) = subject.sequence {
+                    counts[sequence % n_rows] += 1;
+                }
+            }
+            let row = (0..n_rows).min_by_key(|&row| counts[row]).unwrap_or(0);
+            self.subjects.get_mut(*id).unwrap().sequence = Some(row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balanced_inversion() {
+        let experiment: Experiment = crate::test_experiment(
+            "{}",
+            "{format: '{a}', decisive_attribute: a, a: {values: [a, b]}}",
+        )
+        .unwrap();
+        let mut registry = SubjectRegistry::default();
+        for id in &["C1", "C2", "C3", "C4"] {
+            registry.register(id, &experiment).unwrap();
+        }
+        // the choices each subject is trained on
+        let base = registry.correct_choices.clone().unwrap();
+        let trained: Vec<String> = registry
+            .subjects()
+            .map(|(_, subject)| {
+                if subject.inverted {
+                    subject.correct_choices.inverted().hash()
+                } else {
+                    subject.correct_choices.hash()
+                }
+            })
+            .collect();
+        let n_base = trained.iter().filter(|&hash| *hash == base.hash()).count();
+        let n_inverse = trained
+            .iter()
+            .filter(|&h| *h == base.inverted().hash())
+            .count();
+        assert_eq!((n_base, n_inverse), (2, 2));
+        let inverted = registry.get("C1").unwrap().inverted;
+        registry.register("C1", &experiment).unwrap();
+        assert_eq!(registry.get("C1").unwrap().inverted, inverted);
+    }
+}
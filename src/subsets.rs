@@ -0,0 +1,374 @@
+use super::{
+    stimulus::{AttributeFilter, OneOrMany, StimuliConfig},
+    validate::Problem,
+    CorrectChoices, DecisiveValue, Error, Experiment,
+};
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The name of the subset with every stimulus, unless an experiment defines its own
+pub const ALL_SUBSET: &str = "all";
+
+/// One entry of `stimuli_subsets`: either a list of decisive values,
+/// or a combination of other subsets
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(untagged)]
+pub enum SubsetDefinition {
+    Values(Vec<DecisiveValue>),
+    Operation(SubsetOperation),
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsetOperation {
+    Union(Vec<String>),
+    Intersection(Vec<String>),
+    /// the first subset, without the stimuli in any of the others
+    Difference(Vec<String>),
+    /// the stimuli that match an attribute rule
+    Where(AttributeFilter),
+}
+
+/// Evaluates subset definitions to the indices of their stimuli in `StimuliConfig::stimuli`
+pub fn resolve_subsets(
+    definitions: &BTreeMap<String, SubsetDefinition>,
+    config: &StimuliConfig,
+) -> Result<Vec<(String, BTreeSet<usize>)>, Error> {
+    let mut resolver = Resolver {
+        definitions,
+        config,
+        decisive_values: config
+            .stimuli()
+            .iter()
+            .map(|stimulus| stimulus.decisive_value())
+            .collect(),
+        resolved: HashMap::new(),
+        visiting: Vec::new(),
+    };
+    definitions
+        .keys()
+        .map(|name| {
+            let subset = resolver.resolve(name)?;
+            if subset.is_empty() {
+                return Err(Error::EmptySubset(name.clone()));
+            }
+            Ok((name.clone(), subset))
+        })
+        .collect()
+}
+
+/// Lists the problems with each subset definition separately
+pub fn check_subset_definitions(
+    definitions: &BTreeMap<String, SubsetDefinition>,
+    config: &StimuliConfig,
+) -> Vec<Problem> {
+    let decisive_values: Vec<_> = config
+        .stimuli()
+        .iter()
+        .map(|stimulus| stimulus.decisive_value())
+        .collect();
+    definitions
+        .keys()
+        .sorted()
+        .filter_map(|name| {
+            let mut resolver = Resolver {
+                definitions,
+                config,
+                decisive_values: decisive_values.clone(),
+                resolved: HashMap::new(),
+                visiting: Vec::new(),
+            };
+            let error = match resolver.resolve(name) {
+                Ok(subset) if subset.is_empty() => Error::EmptySubset(name.clone()),
+                Ok(_) => return None,
+                Err(e) => e,
+            };
+            Some(Problem::new(
+                format!("decide.stimuli_subsets.{}", name),
+                error,
+            ))
+        })
+        .collect()
+}
+
+struct Resolver<'a> {
+    definitions: &'a BTreeMap<String, SubsetDefinition>,
+    config: &'a StimuliConfig,
+    decisive_values: Vec<DecisiveValue>,
+    resolved: HashMap<&'a str, BTreeSet<usize>>,
+    visiting: Vec<&'a str>,
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve(&mut self, name: &'a str) -> Result<BTreeSet<usize>, Error> {
+        if let Some(subset) = self.resolved.get(name) {
+            return Ok(subset.clone());
+        }
+        if self.visiting.contains(&name) {
+            return Err(Error::SubsetCycle(name.into()));
+        }
+        let definition = match self.definitions.get(name) {
+            Some(definition) => definition,
+            None if name == ALL_SUBSET => {
+                return Ok((0..self.decisive_values.len()).collect());
+            }
+            None => return Err(Error::UnknownSubset(name.into())),
+        };
+        self.visiting.push(name);
+        let subset = match definition {
+            SubsetDefinition::Values(values) => {
+                if !values.iter().all(|v| self.decisive_values.contains(v)) {
+                    return Err(Error::NotASubset(name.into()));
+                }
+                self.indices(|i, _| values.contains(&self.decisive_values[i]))
+            }
+            SubsetDefinition::Operation(SubsetOperation::Union(names)) => {
+                let mut subset = BTreeSet::new();
+                for other in names.iter() {
+                    subset.extend(self.resolve(other)?);
+                }
+                subset
+            }
+            SubsetDefinition::Operation(SubsetOperation::Intersection(names)) => {
+                let mut subsets = names
+                    .iter()
+                    .map(|other| self.resolve(other))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter();
+                let first = subsets.next().unwrap_or_default();
+                subsets.fold(first, |acc, subset| &acc & &subset)
+            }
+            SubsetDefinition::Operation(SubsetOperation::Difference(names)) => {
+                let mut subsets = names
+                    .iter()
+                    .map(|other| self.resolve(other))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter();
+                let first = subsets.next().unwrap_or_default();
+                subsets.fold(first, |acc, subset| &acc - &subset)
+            }
+            SubsetDefinition::Operation(SubsetOperation::Where(filter)) => {
+                if let Some(label) = filter
+                    .labels()
+                    .find(|label| self.config.list_values(label).is_none())
+                {
+                    return Err(Error::UnknownAttributeInRule(label.to_string()));
+                }
+                self.indices(|_, stimulus| stimulus.matches_filter(filter))
+            }
+        };
+        self.visiting.pop();
+        self.resolved.insert(name, subset.clone());
+        Ok(subset)
+    }
+
+    fn indices<F>(&self, predicate: F) -> BTreeSet<usize>
+    where
+        F: Fn(usize, &super::Stimulus) -> bool,
+    {
+        self.config
+            .stimuli()
+            .iter()
+            .enumerate()
+            .filter(|(i, stimulus)| predicate(*i, stimulus))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Settings for generating `stimuli_subsets` instead of listing them
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    split: SplitKind,
+    k: usize,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    balance: OneOrMany<BalanceBy>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Debug)]
+pub enum SplitKind {
+    /// `k` test sets that partition the decisive values, each with a training set of the rest
+    #[serde(rename = "k-fold")]
+    KFold,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceBy {
+    Response,
+    Category,
+}
+
+/// Generated subsets, saved so that they stay the same when configs are regenerated
+#[derive(Serialize, Deserialize)]
+pub struct SubsetLock {
+    pub split: SplitConfig,
+    pub subsets: BTreeMap<String, Vec<DecisiveValue>>,
+}
+
+impl SplitConfig {
+    pub fn validate(&self, n_values: usize) -> Result<(), Error> {
+        match self.split {
+            SplitKind::KFold if self.k < 2 || self.k > n_values => {
+                Err(Error::InvalidSplit(format!(
+                    "k must be between 2 and the number of decisive values ({})",
+                    n_values
+                )))
+            }
+            SplitKind::KFold => Ok(()),
+        }
+    }
+
+    pub fn generate(
+        &self,
+        experiment: &Experiment,
+        correct_choices: &CorrectChoices,
+    ) -> Result<SubsetLock, Error> {
+        let values = experiment.decisive_values();
+        self.validate(values.len())?;
+        let categories: HashMap<_, _> = experiment
+            .stimuli()
+            .iter()
+            .map(|stimulus| (stimulus.decisive_value(), stimulus.category().cloned()))
+            .collect();
+        // values that share a response and/or category are dealt out to the folds together,
+        // so that each fold gets as even a share of every group as possible
+        let groups = values
+            .into_iter()
+            .map(|value| {
+                let mut key = Vec::new();
+                for by in self.balance.as_slice() {
+                    match by {
+                        BalanceBy::Response => {
+                            let response = correct_choices.response(&value).ok_or_else(|| {
+                                Error::StimMissingFromCorrectChoices(value.clone())
+                            })?;
+                            key.push(format!("{:?}", response));
+                        }
+                        BalanceBy::Category => key.push(
+                            categories[&value]
+                                .as_ref()
+                                .map(ToString::to_string)
+                                .unwrap_or_default(),
+                        ),
+                    }
+                }
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>, Error>>()?
+            .into_iter()
+            .into_group_map();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut folds = vec![Vec::new(); self.k];
+        let mut next_fold = 0;
+        for (_, mut group) in groups.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+            group.sort_by_key(ToString::to_string);
+            group.shuffle(&mut rng);
+            for value in group {
+                folds[next_fold].push(value);
+                next_fold = (next_fold + 1) % self.k;
+            }
+        }
+        let mut subsets = BTreeMap::new();
+        for (i, fold) in folds.iter().enumerate() {
+            let train = folds
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .flat_map(|(_, other)| other.iter().cloned())
+                .collect();
+            subsets.insert(format!("train{}", i + 1), train);
+            subsets.insert(format!("test{}", i + 1), fold.clone());
+        }
+        Ok(SubsetLock {
+            split: self.clone(),
+            subsets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Response;
+
+    fn experiment_with_subsets(subsets: &str) -> Result<Experiment, serde_yaml::Error> {
+        crate::test_experiment(
+            &format!("{{stimuli_subsets: {}}}", subsets),
+            "
+            format: '{a}_{b}'
+            decisive_attribute: a
+            a:
+                values: [w, x, y, z]
+            b:
+                values: [1, 2]
+            ",
+        )
+    }
+
+    #[test]
+    fn composite_subsets() {
+        let experiment = experiment_with_subsets(
+            "{
+                heldout: [w],
+                loud: {where: {b: 2}},
+                probe: {intersection: [loud, heldout]},
+                train: {difference: [all, heldout]},
+                transfer: {union: [train, probe]},
+            }",
+        )
+        .unwrap();
+        let sizes: HashMap<_, _> = experiment
+            .stimuli_subsets()
+            .unwrap()
+            .into_iter()
+            .map(|(name, set)| (name, set.len()))
+            .collect();
+        assert_eq!(sizes["probe"], 1);
+        assert_eq!(sizes["train"], 6);
+        assert_eq!(sizes["transfer"], 7);
+    }
+
+    #[test]
+    fn invalid_composite_subsets() {
+        let cycle = experiment_with_subsets("{a: {union: [b]}, b: {union: [a]}}");
+        assert!(cycle.err().unwrap().to_string().contains("cycle"));
+        let empty = experiment_with_subsets("{a: [w], b: [x], c: {intersection: [a, b]}}");
+        assert!(empty.err().unwrap().to_string().contains("empty"));
+        let unknown = experiment_with_subsets("{a: {union: [b]}}");
+        assert!(unknown.err().unwrap().to_string().contains("unknown"));
+    }
+
+    #[test]
+    fn balanced_k_fold() {
+        let experiment: Experiment = crate::test_experiment(
+            "{stimuli_subsets: {split: k-fold, k: 2, seed: 1, balance: response}}",
+            "{format: '{a}', decisive_attribute: a, a: {values: [a, b, c, d, e, f, g, h]}}",
+        )
+        .unwrap();
+        let correct_choices = CorrectChoices::random(&experiment, 7).unwrap();
+        let split = experiment.subset_generator().unwrap();
+        let lock = split.generate(&experiment, &correct_choices).unwrap();
+        assert_eq!(lock.subsets.len(), 4);
+        for i in 1..=2 {
+            let test = &lock.subsets[&format!("test{}", i)];
+            let train = &lock.subsets[&format!("train{}", i)];
+            assert_eq!(test.len(), 4);
+            assert_eq!(train.len(), 4);
+            assert!(test.iter().all(|value| !train.contains(value)));
+            let left = test
+                .iter()
+                .filter(|value| correct_choices.response(value) == Some(&Response::PeckLeft))
+                .count();
+            assert_eq!(left, 2);
+        }
+        let again = split.generate(&experiment, &correct_choices).unwrap();
+        assert_eq!(again.subsets, lock.subsets);
+    }
+}
@@ -0,0 +1,259 @@
+use super::{experiment::UnvalidatedExperiment, Error, ExperimentFormat, ExperimentSources};
+use std::{collections::HashMap, fmt};
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+/// Something wrong with an experiment, and the path to where it is in the experiment
+/// file, such as `stimuli.fg_db.values[2]`
+#[derive(Debug)]
+pub struct Problem {
+    pub path: String,
+    pub error: Error,
+}
+
+impl Problem {
+    pub fn new<P: Into<String>>(path: P, error: Error) -> Self {
+        Problem {
+            path: path.into(),
+            error,
+        }
+    }
+}
+
+/// A problem with an experiment file, with the file and its line and column if they
+/// could be found
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub location: Option<(usize, usize)>,
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = self.file.as_ref() {
+            write!(f, "{}:", file)?;
+        }
+        if let Some((line, column)) = self.location {
+            write!(f, "{}:{}:", line, column)?;
+        }
+        if self.file.is_some() || self.location.is_some() {
+            write!(f, " ")?;
+        }
+        if let Some(path) = self.path.as_ref() {
+            write!(f, "{}: ", path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Lists every problem with an experiment file, instead of stopping at the first one
+pub fn validate(source: &str) -> Vec<Diagnostic> {
+    let locations = match SourceMap::parse(source) {
+        Ok(locations) => locations,
+        Err(e) => {
+            let marker = e.marker();
+            return vec![Diagnostic {
+                file: None,
+                location: Some((marker.line(), marker.col() + 1)),
+                path: None,
+                message: e.to_string(),
+            }];
+        }
+    };
+    let experiment: UnvalidatedExperiment = match serde_yaml::from_str(source) {
+        Ok(experiment) => experiment,
+        Err(e) => {
+            return vec![Diagnostic {
+                file: None,
+                location: e.location().map(|l| (l.line(), l.column())),
+                path: None,
+                message: e.to_string(),
+            }]
+        }
+    };
+    match experiment.check() {
+        Ok(_) => Vec::new(),
+        Err(problems) => problems
+            .into_iter()
+            .map(|problem| Diagnostic {
+                file: None,
+                location: locations.find(&problem.path),
+                message: problem.error.to_string(),
+                path: Some(problem.path),
+            })
+            .collect(),
+    }
+}
+
+/// Like `validate`, with each problem placed in the file it came from. Locations in an
+/// experiment that extends or includes other files are found in whichever file last
+/// set the key, and problems in TOML files are given without one. Problems that
+/// can't be traced to a file, such as the merged experiment not parsing, are placed
+/// in the output of `render`.
+pub fn validate_sources(sources: &ExperimentSources) -> Vec<Diagnostic> {
+    let mut diagnostics = validate(&sources.source);
+    for diagnostic in diagnostics.iter_mut() {
+        if !sources.is_merged() {
+            diagnostic.file = Some(sources.name().to_string());
+            continue;
+        }
+        let origin = diagnostic
+            .path
+            .as_deref()
+            .and_then(|path| Some((path, sources.origin(path)?)));
+        match origin {
+            Some((path, file)) => {
+                diagnostic.file = Some(file.name.clone());
+                // TOML can't be read as YAML, so only the file is given
+                diagnostic.location = match file.format {
+                    ExperimentFormat::Toml => None,
+                    _ => SourceMap::parse(&file.text)
+                        .ok()
+                        .and_then(|locations| locations.find(path)),
+                };
+            }
+            None => diagnostic.file = Some(format!("{} (rendered)", sources.name())),
+        }
+    }
+    diagnostics
+}
+
+/// The line and column of each mapping key and sequence item in a YAML document
+#[derive(Default)]
+pub struct SourceMap {
+    locations: HashMap<String, (usize, usize)>,
+    stack: Vec<Node>,
+}
+
+enum Node {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize },
+}
+
+impl SourceMap {
+    pub fn parse(source: &str) -> Result<Self, yaml_rust::ScanError> {
+        let mut map = SourceMap::default();
+        Parser::new(source.chars()).load(&mut map, false)?;
+        Ok(map)
+    }
+
+    /// The location of `path`, or of its closest parent that is in the document
+    pub fn find(&self, path: &str) -> Option<(usize, usize)> {
+        let mut path = path;
+        loop {
+            if let Some(location) = self.locations.get(path) {
+                return Some(*location);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+
+    /// The path of a node that is starting, or `None` if it is a mapping key
+    fn enter(&mut self, event: &Event, marker: Marker) -> Option<String> {
+        let location = (marker.line(), marker.col() + 1);
+        let path = match self.stack.last_mut() {
+            None => String::new(),
+            Some(Node::Mapping { path, key }) => match key.take() {
+                Some(key) => join(path, &key),
+                None => {
+                    let key_name = match event {
+                        Event::Scalar(value, ..) => value.clone(),
+                        _ => String::from("?"),
+                    };
+                    self.locations.insert(join(path, &key_name), location);
+                    *key = Some(key_name);
+                    return None;
+                }
+            },
+            Some(Node::Sequence { path, index }) => {
+                let item = format!("{}[{}]", path, index);
+                *index += 1;
+                self.locations.insert(item.clone(), location);
+                item
+            }
+        };
+        Some(path)
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.into()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+impl MarkedEventReceiver for SourceMap {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(..) | Event::Alias(..) => {
+                self.enter(&event, marker);
+            }
+            Event::MappingStart(..) => {
+                // mappings used as keys are ignored, but still need a node on the stack
+                let path = self.enter(&event, marker).unwrap_or_default();
+                self.stack.push(Node::Mapping { path, key: None });
+            }
+            Event::SequenceStart(..) => {
+                let path = self.enter(&event, marker).unwrap_or_default();
+                self.stack.push(Node::Sequence { path, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_problems() {
+        let diagnostics = validate(
+            "\
+decide:
+  parameters:
+  name_format: '{fg_db}-{missing}'
+  stimulus_root: /
+  choices: [peck_left, peck_left]
+  stimuli_subsets:
+    first: [a, z]
+stimuli:
+  format: '{foreground}'
+  decisive_attribute: foreground
+  foreground:
+    values: [a, b, a]
+  fg_db:
+    values: [30]
+",
+        );
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.path.as_deref().unwrap(), d.location.unwrap()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("decide.choices", (5, 3)),
+                ("stimuli.foreground.values[2]", (12, 20)),
+                ("decide.name_format", (3, 3)),
+                ("decide.stimuli_subsets.first", (7, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_error_location() {
+        let diagnostics = validate("decide: [\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].location.is_some());
+    }
+}